@@ -0,0 +1,63 @@
+//! Lock free fixed size slot allocator.
+// Hands out small integer ids (pids, file descriptors, ...) from
+// a fixed pool. Each bit is one slot, claimed with `fetch_or` and
+// released with `fetch_and`, so no lock is needed across harts.
+use core::sync::atomic::*;
+
+// Pool of `N` words, i.e. `64 * N` slots.
+pub struct BitmapAllocator<const N: usize> {
+    words: [AtomicU64; N],
+}
+
+impl<const N: usize> Default for BitmapAllocator<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> BitmapAllocator<N> {
+    pub const CAPACITY: usize = N * 64;
+
+    pub const fn new() -> Self {
+        BitmapAllocator {
+            words: [const { AtomicU64::new(0) }; N],
+        }
+    }
+
+    // Claim the lowest free slot, or None if the pool is full.
+    pub fn alloc(&self) -> Option<usize> {
+        for (i, word) in self.words.iter().enumerate() {
+            let mut cur = word.load(Ordering::Relaxed);
+            while cur != u64::MAX {
+                let bit = 1 << (!cur).trailing_zeros();
+                // Another hart may take the same bit between the load
+                // and here; the returned old value tells us who won.
+                let prev = word.fetch_or(bit, Ordering::Acquire);
+                if prev & bit == 0 {
+                    return Some(i * 64 + bit.trailing_zeros() as usize);
+                }
+                cur = prev | bit;
+            }
+        }
+        None
+    }
+
+    // Claim slot `idx` specifically. False if it's already taken.
+    pub fn try_claim(&self, idx: usize) -> bool {
+        assert!(idx < Self::CAPACITY, "bitmap claim out of range");
+        let bit = 1 << (idx % 64);
+        self.words[idx / 64].fetch_or(bit, Ordering::Acquire) & bit == 0
+    }
+
+    // Release a slot previously returned by alloc() or try_claim().
+    pub fn free(&self, idx: usize) {
+        assert!(idx < Self::CAPACITY, "bitmap free out of range");
+        let bit = 1 << (idx % 64);
+        let prev = self.words[idx / 64].fetch_and(!bit, Ordering::Release);
+        debug_assert!(prev & bit != 0, "bitmap double free");
+    }
+
+    pub fn is_allocated(&self, idx: usize) -> bool {
+        self.words[idx / 64].load(Ordering::Relaxed) & (1 << (idx % 64)) != 0
+    }
+}
@@ -0,0 +1,56 @@
+//! Boot ordering.
+// Subsystems have to come up in a fixed order. Each one advances
+// the global stage when it's done, and anything with prerequisites
+// checks them with require_stage(), so init run out of order panics
+// with the names of both stages instead of faulting mysteriously.
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum BootStage {
+    Reset = 0,
+    Uart,
+    Allocator,
+    Vm,
+    Traps,
+    Plic,
+    Smp,
+    Scheduler,
+}
+
+impl BootStage {
+    const ALL: [BootStage; 8] = [
+        BootStage::Reset,
+        BootStage::Uart,
+        BootStage::Allocator,
+        BootStage::Vm,
+        BootStage::Traps,
+        BootStage::Plic,
+        BootStage::Smp,
+        BootStage::Scheduler,
+    ];
+}
+
+static STAGE: AtomicU8 = AtomicU8::new(BootStage::Reset as u8);
+
+pub fn current_stage() -> BootStage {
+    BootStage::ALL[STAGE.load(Ordering::Acquire) as usize]
+}
+
+// Mark `stage` as done. It must be the one right after the current
+// stage; skipping or repeating one means init is out of order.
+pub fn advance_stage(stage: BootStage) {
+    let cur = current_stage();
+    if stage as u8 != cur as u8 + 1 {
+        panic!("boot stage {:?} reached from {:?}", stage, cur);
+    }
+    STAGE.store(stage as u8, Ordering::Release);
+}
+
+// Panic unless boot has got at least as far as `stage`.
+pub fn require_stage(stage: BootStage) {
+    let cur = current_stage();
+    if cur < stage {
+        panic!("needs boot stage {:?}, but only at {:?}", stage, cur);
+    }
+}
@@ -0,0 +1,132 @@
+//! Time sources.
+// The same wall clock can be read several ways (CLINT mtime, the
+// time CSR, later maybe an RTC or SBI), and which one works depends
+// on the platform and privilege mode. Everything that needs the time
+// goes through the Clock picked at boot instead of a specific register.
+use crate::lateinit::LateInit;
+use crate::log::{log, print};
+use crate::param::{CLINT_BASE, TIMEBASE_HZ};
+use crate::riscv::{clint_mtime, read_time};
+
+pub trait Clock: Sync {
+    // Current tick count.
+    fn now(&self) -> u64;
+    // Ticks per second.
+    fn freq(&self) -> u64;
+}
+
+// The CLINT's memory mapped mtime register.
+pub struct ClintClock {
+    base: usize,
+}
+
+impl ClintClock {
+    pub const fn new(base: usize) -> Self {
+        ClintClock { base }
+    }
+}
+
+impl Clock for ClintClock {
+    fn now(&self) -> u64 {
+        let mtime = clint_mtime(self.base) as *const u64;
+        unsafe { mtime.read_volatile() }
+    }
+
+    fn freq(&self) -> u64 {
+        TIMEBASE_HZ
+    }
+}
+
+// The time CSR (rdtime). Readable from sup mode since _start
+// sets mcounteren.TM.
+pub struct TimeCsrClock;
+
+impl Clock for TimeCsrClock {
+    fn now(&self) -> u64 {
+        read_time()
+    }
+
+    fn freq(&self) -> u64 {
+        TIMEBASE_HZ
+    }
+}
+
+pub static CLINT_CLOCK: ClintClock = ClintClock::new(CLINT_BASE);
+pub static TIME_CSR_CLOCK: TimeCsrClock = TimeCsrClock;
+
+static CLOCK: LateInit<&'static dyn Clock> = LateInit::new();
+
+// Boot self test parameters. How long to wait for a clock to move
+// before calling it dead, how many times to compare the two, and
+// how far apart they may read: reads aren't simultaneous, so allow
+// a millisecond.
+const CHECK_SPINS: usize = 100_000;
+const CHECK_SAMPLES: usize = 4;
+const SKEW_TOLERANCE: u64 = TIMEBASE_HZ / 1000;
+
+// Does `clock` tick at all?
+fn advances(clock: &dyn Clock) -> bool {
+    let start = clock.now();
+    (0..CHECK_SPINS).any(|_| {
+        core::hint::spin_loop();
+        clock.now() != start
+    })
+}
+
+// Should be the same counter, but on some platforms the time CSR
+// isn't wired to mtime (or traps to slow firmware emulation).
+fn clocks_agree() -> bool {
+    (0..CHECK_SAMPLES).all(|_| {
+        let mtime = CLINT_CLOCK.now();
+        let time = TIME_CSR_CLOCK.now();
+        // Read second, so time should be at or just past mtime.
+        time.wrapping_sub(mtime) <= SKEW_TOLERANCE
+    })
+}
+
+// Pick the system clock. The time CSR is the cheaper read and we
+// grant it to sup mode ourselves, so prefer it, unless a quick
+// self test says it doesn't follow the CLINT. Everything from
+// timeouts to the scheduler quantum trusts this choice.
+pub fn init() {
+    let time_ok = advances(&TIME_CSR_CLOCK);
+    let mtime_ok = advances(&CLINT_CLOCK);
+    let clock: &'static dyn Clock = match (time_ok, mtime_ok) {
+        (true, true) if clocks_agree() => &TIME_CSR_CLOCK,
+        (true, true) => {
+            log!(
+                Warning,
+                "time CSR disagrees with CLINT mtime, using the CLINT"
+            );
+            &CLINT_CLOCK
+        }
+        (false, true) => {
+            log!(Warning, "time CSR not advancing, using the CLINT");
+            &CLINT_CLOCK
+        }
+        (true, false) => &TIME_CSR_CLOCK,
+        (false, false) => {
+            log!(Error, "no clock is advancing");
+            &TIME_CSR_CLOCK
+        }
+    };
+    CLOCK.init(clock);
+}
+
+pub fn clock() -> &'static dyn Clock {
+    *CLOCK.get()
+}
+
+pub fn uptime_ms() -> u64 {
+    let clock = clock();
+    clock.now() / (clock.freq() / 1000)
+}
+
+// Busy wait for at least `ms` milliseconds.
+pub fn delay_ms(ms: u64) {
+    let clock = clock();
+    let until = clock.now() + ms * (clock.freq() / 1000);
+    while clock.now() < until {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,386 @@
+//! Per hart helpers.
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::log::{log, print};
+use crate::param::NHART;
+#[cfg(feature = "heartbeat")]
+use crate::param::{HEARTBEAT_SECS, TIMEBASE_HZ};
+use crate::riscv::*;
+
+// Hart 0 boots the kernel alone; other harts wait on this
+// flag before touching any shared kernel state.
+static SMP_STARTED: AtomicBool = AtomicBool::new(false);
+
+// Release the other harts. Called by hart 0 once bootstrap is done.
+// Everything written before this is visible to them (Release).
+pub fn smp_start() {
+    SMP_STARTED.store(true, Ordering::Release);
+}
+
+pub fn smp_started() -> bool {
+    SMP_STARTED.load(Ordering::Acquire)
+}
+
+// Bit per hart that has made it into main(). QEMU may be
+// started with fewer than NHART harts, so code that targets
+// "every other hart" should go by this rather than NHART.
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+
+// Called by each hart once it reaches main().
+//
+// This is also how a hart publishes its per hart state. The
+// fetch_or is Release and every read of the mask is Acquire, so
+// anything this hart wrote before marking itself online is visible
+// to whoever sees its bit. Finish setting up per hart state (its
+// timer scratch row and the like) *before* calling this, and only
+// read another hart's state after seeing its bit:
+//
+//     hart 1                       hart 0
+//     TIMER_STATE[1] = ...;        if is_online(1) {
+//     mark_online();                   read TIMER_STATE[1]  // fine
+//                                  }
+//
+// Anything written after mark_online() needs its own ordering.
+pub fn mark_online() {
+    ONLINE.fetch_or(1 << read_tp(), Ordering::Release);
+}
+
+// Every reader of ONLINE goes through here, see mark_online().
+pub fn online_mask() -> u64 {
+    ONLINE.load(Ordering::Acquire)
+}
+
+pub fn online_hart_count() -> u32 {
+    online_mask().count_ones()
+}
+
+pub fn is_online(hartid: u64) -> bool {
+    online_mask() & (1 << hartid) != 0
+}
+
+// One T per hart, each hart only touching its own (indexed by tp).
+// State a trap handler on the same hart also touches should stay
+// atomic and go through get().
+pub struct PerCpu<T> {
+    slots: UnsafeCell<[T; NHART]>,
+}
+
+// Each slot is only used by its own hart.
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(init: [T; NHART]) -> Self {
+        PerCpu {
+            slots: UnsafeCell::new(init),
+        }
+    }
+
+    // Pointer to this hart's slot, without making a reference to
+    // the whole array (and so to the other harts' slots).
+    fn slot(&self) -> *mut T {
+        let hart = read_tp() as usize;
+        assert!(hart < NHART);
+        unsafe { (self.slots.get() as *mut T).add(hart) }
+    }
+
+    pub fn get(&self) -> &T {
+        unsafe { &*self.slot() }
+    }
+
+    // Interrupts must be off, or a trap handler on this hart could
+    // take a second reference to the same slot.
+    /// # Safety
+    ///
+    /// No other reference to this hart's slot may be live,
+    /// including ones from get().
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_mut(&self) -> &mut T {
+        debug_assert!(!interrupts_enabled(), "PerCpu::get_mut with interrupts on");
+        &mut *self.slot()
+    }
+}
+
+// Bring-up aid: idle harts stamp the time here as they spin, and
+// log a line every HEARTBEAT_SECS, so a secondary hart that died
+// during boot shows up as a stale or missing timestamp.
+#[cfg(feature = "heartbeat")]
+static LAST_SEEN: [AtomicU64; NHART] = [const { AtomicU64::new(0) }; NHART];
+#[cfg(feature = "heartbeat")]
+static LAST_REPORT: [AtomicU64; NHART] = [const { AtomicU64::new(0) }; NHART];
+
+// Call from the idle loop.
+#[cfg(feature = "heartbeat")]
+pub fn heartbeat() {
+    let hart = read_tp() as usize;
+    let now = read_time();
+    LAST_SEEN[hart].store(now, Ordering::Relaxed);
+    if now - LAST_REPORT[hart].load(Ordering::Relaxed) >= HEARTBEAT_SECS * TIMEBASE_HZ {
+        LAST_REPORT[hart].store(now, Ordering::Relaxed);
+        log!(Debug, "hart {} alive", hart);
+    }
+}
+
+// time value at which `hartid` last checked in, 0 if never.
+#[cfg(feature = "heartbeat")]
+pub fn last_seen(hartid: usize) -> u64 {
+    LAST_SEEN[hartid].load(Ordering::Relaxed)
+}
+
+// Preemption control. Unlike turning interrupts off, this only
+// stops the timer from rescheduling us; interrupts are still taken.
+// Per hart nesting depth, and whether a reschedule was skipped
+// while it was non zero. Only ever touched by the owning hart.
+static PREEMPT_COUNT: PerCpu<AtomicUsize> = PerCpu::new([const { AtomicUsize::new(0) }; NHART]);
+static NEED_RESCHED: PerCpu<AtomicBool> = PerCpu::new([const { AtomicBool::new(false) }; NHART]);
+
+pub fn preempt_disable() {
+    PREEMPT_COUNT.get().fetch_add(1, Ordering::Relaxed);
+}
+
+// Returns true if a reschedule was deferred while preemption was
+// off and is due now; the caller should yield.
+pub fn preempt_enable() -> bool {
+    // Check before decrementing, so a stray call panics without
+    // wrapping the count and leaving us non preemptible for good.
+    let prev = PREEMPT_COUNT
+        .get()
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+        .expect("preempt_enable without preempt_disable");
+    prev == 1 && NEED_RESCHED.get().swap(false, Ordering::Relaxed)
+}
+
+pub fn preemptible() -> bool {
+    PREEMPT_COUNT.get().load(Ordering::Relaxed) == 0
+}
+
+// For the timer path: may we reschedule right now? If not, the
+// request is remembered and preempt_enable() reports it later.
+pub fn try_preempt() -> bool {
+    if preemptible() {
+        true
+    } else {
+        NEED_RESCHED.get().store(true, Ordering::Relaxed);
+        false
+    }
+}
+
+// Mask some interrupt classes (SIE_STIE, SIE_SEIE, ...) for as long
+// as the guard lives, e.g. around reprogramming the timer. Finer
+// grained than turning interrupts off entirely. Only the bits that
+// were enabled before are turned back on, so guards nest:
+//
+//     let _t = mask_interrupts(SIE_STIE);
+//     let _e = mask_interrupts(SIE_STIE | SIE_SEIE);
+//     // _e drops: SEIE back on, STIE stays off for _t
+//
+// Guards must be dropped in reverse order, which scoping gives you.
+pub struct MaskGuard {
+    restore: u64,
+}
+
+pub fn mask_interrupts(mask: u64) -> MaskGuard {
+    MaskGuard {
+        restore: clear_sie(mask) & mask,
+    }
+}
+
+impl Drop for MaskGuard {
+    fn drop(&mut self) {
+        set_sie(self.restore);
+    }
+}
+
+// Trap nesting depth per hart, to catch the trap handler itself
+// faulting. Without this a bad dereference in kerneltrap traps
+// again, clobbering sepc/scause, and loops forever. The outer
+// trap's cause is kept so the report can show both.
+static TRAP_DEPTH: PerCpu<AtomicUsize> = PerCpu::new([const { AtomicUsize::new(0) }; NHART]);
+static OUTER_TRAP: PerCpu<[AtomicU64; 3]> =
+    PerCpu::new([const { [const { AtomicU64::new(0) }; 3] }; NHART]);
+
+// Call first thing in kerneltrap, before anything that could fault.
+// A trap taken while already handling one reports both and halts
+// this hart.
+pub fn trap_enter() {
+    let hart = read_tp() as usize;
+    let (scause, sepc, stval) = (read_scause(), read_sepc(), read_stval());
+    if TRAP_DEPTH.get().fetch_add(1, Ordering::Relaxed) == 0 {
+        for (slot, val) in OUTER_TRAP.get().iter().zip([scause, sepc, stval]) {
+            slot.store(val, Ordering::Relaxed);
+        }
+        return;
+    }
+
+    let outer = OUTER_TRAP.get();
+    log!(Error, "double fault on hart {}", hart);
+    log!(
+        Error,
+        "  first:  scause {:#x} sepc {:#x} stval {:#x}",
+        outer[0].load(Ordering::Relaxed),
+        outer[1].load(Ordering::Relaxed),
+        outer[2].load(Ordering::Relaxed)
+    );
+    log!(
+        Error,
+        "  nested: scause {:#x} sepc {:#x} stval {:#x}",
+        scause,
+        sepc,
+        stval
+    );
+    log!(Error, "  sstatus: {:?}", Sstatus(read_sstatus()));
+    park()
+}
+
+// Call on the way out of kerneltrap.
+pub fn trap_exit() {
+    TRAP_DEPTH.get().fetch_sub(1, Ordering::Relaxed);
+}
+
+// Sleep until an interrupt arrives, with supervisor interrupts
+// enabled only for the duration of the wfi.
+//
+// The race: if we enabled SIE, checked for work, and then executed
+// wfi, an interrupt landing between the check and the wfi would be
+// taken and handled, and we would then sleep on an event that has
+// already happened. So callers check for work with interrupts off,
+// and SIE goes up immediately before the wfi.
+//
+// That is safe because wfi wakes when an interrupt is *pending* and
+// enabled in sie, regardless of sstatus.SIE (priv spec 3.3.3). An
+// interrupt that arrives in the window is still pending when wfi
+// executes, so we don't sleep through it, and with SIE set the trap
+// is taken right after. wfi may also return spuriously, so callers
+// must recheck their condition in a loop.
+pub fn wait_for_interrupt() {
+    intr_on();
+    wfi();
+    intr_off();
+}
+
+// Hartid + 1 of the hart that is panicking, 0 if none.
+static PANICKING: AtomicU32 = AtomicU32::new(0);
+
+// Called by the panic handler. Only one hart wins; it gets to print
+// and bring the rest down with halt_all_harts(). Everyone else
+// should park() without printing.
+pub fn claim_panic() -> bool {
+    PANICKING
+        .compare_exchange(0, read_tp() as u32 + 1, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+}
+
+// Hart that claimed the panic, if any.
+pub fn panicking_hart() -> Option<u32> {
+    PANICKING.load(Ordering::Acquire).checked_sub(1)
+}
+
+// Spins before spin_then_wfi() gives up and sleeps.
+const SPINS_BEFORE_WFI: usize = 10_000;
+
+// Wait for `cond` without a scheduler to yield to, e.g. secondary
+// harts waiting at a boot rendezvous. Short waits are caught by
+// spinning; after that the hart sleeps in wfi instead of burning
+// the core.
+//
+// Interrupts stay off (unlike wait_for_interrupt(), this is for
+// before there is a trap handler). wfi still wakes when anything
+// enabled in sie is pending, and timervec raises SSIP every tick,
+// so we recheck at least once per tick. Nothing else in sup mode
+// clears SSIP yet, and left pending it would make every wfi return
+// at once, so we clear it before each check. wfi may also return
+// for no reason at all, hence the loop: `cond` is checked after
+// every wake, and must be cheap and side effect free.
+//
+// A hart waiting here is online, so it keeps answering TLB
+// shootdowns and halt requests; otherwise their initiator would
+// wait on us.
+pub fn spin_then_wfi(cond: impl Fn() -> bool) {
+    for _ in 0..SPINS_BEFORE_WFI {
+        if cond() {
+            return;
+        }
+        if halt_requested() {
+            park();
+        }
+        crate::vm::handle_tlb_shootdown();
+        spin_loop();
+    }
+    loop {
+        // Clear before checking: a tick landing after the check
+        // still wakes the wfi below.
+        clear_sip(SIP_SSIP);
+        if halt_requested() {
+            park();
+        }
+        crate::vm::handle_tlb_shootdown();
+        if cond() {
+            return;
+        }
+        wfi();
+    }
+}
+
+// Set by halt_all_harts(). Other harts check it and park.
+static HALT_REQUESTED: AtomicBool = AtomicBool::new(false);
+// Bit per hart, set once that hart has parked.
+static PARKED: AtomicU64 = AtomicU64::new(0);
+
+// How long halt_all_harts() waits for the others to park
+// before giving up on them and stopping anyway.
+const HALT_ACK_SPINS: usize = 1_000_000;
+
+pub fn halt_requested() -> bool {
+    HALT_REQUESTED.load(Ordering::Acquire)
+}
+
+// Acknowledge a halt request and stop this hart for good.
+//
+// A parked hart drops out of the online mask, so nothing that
+// targets online harts (TLB shootdowns, say) waits on it.
+pub fn park() -> ! {
+    PARKED.fetch_or(1 << read_tp(), Ordering::Release);
+    ONLINE.fetch_and(!(1 << read_tp()), Ordering::Release);
+    loop {
+        wfi();
+    }
+}
+
+// Stop every hart, e.g. on a fatal error, so nobody keeps
+// running on corrupt state. Stops the others, then parks this
+// hart as well.
+pub fn halt_all_harts() -> ! {
+    stop_other_harts();
+    park()
+}
+
+// Raise the halt flag, poke each other hart with a CLINT software
+// interrupt and wait briefly for them to park. Returns either way;
+// a hart that never parks is stuck somewhere we can't reach.
+//
+// No machine mode handler demuxes msip yet (mie.MSIE is off and
+// timervec assumes every trap is the timer), so for now the other
+// harts notice the flag by polling halt_requested() in their idle
+// loop. The IPI is sent anyway so an msip handler can hook in.
+//
+// Only one hart runs the halt. If another got here first (e.g. a
+// hart that lost claim_panic() to it and halted anyway) we park
+// straight away, or the two would wait on each other to park.
+pub fn stop_other_harts() {
+    if HALT_REQUESTED.swap(true, Ordering::AcqRel) {
+        park()
+    }
+    let me = read_tp();
+    for hart in (0..NHART as u64).filter(|&h| h != me && is_online(h)) {
+        clint_send_ipi(hart);
+    }
+
+    let others = online_mask() & !(1 << me);
+    for _ in 0..HALT_ACK_SPINS {
+        if PARKED.load(Ordering::Acquire) & others == others {
+            break;
+        }
+        spin_loop();
+    }
+}
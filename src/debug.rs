@@ -0,0 +1,58 @@
+//! Debugging aids.
+use core::fmt::Write;
+
+use crate::log::{print, println, ByteWriter};
+
+const BYTES_PER_LINE: usize = 16;
+
+// Print [addr, addr + len) as hex, 16 bytes a line, with the
+// address on the left and printable ASCII on the right:
+//   0000000080001000: 13 05 00 00 ...  |....|
+// Every byte is read with its own volatile load, so this is fine
+// on device registers too (keeping in mind that reading some of
+// them, like the UART's RBR, has side effects).
+/// # Safety
+///
+/// The whole range must be readable.
+pub unsafe fn hexdump(addr: usize, len: usize) {
+    for line in (addr..addr + len).step_by(BYTES_PER_LINE) {
+        let n = core::cmp::min(BYTES_PER_LINE, addr + len - line);
+        let mut bytes = [0u8; BYTES_PER_LINE];
+        for (i, b) in bytes.iter_mut().take(n).enumerate() {
+            *b = ((line + i) as *const u8).read_volatile();
+        }
+
+        // Build the whole line first so lines from other harts
+        // can't land in the middle of it.
+        let mut buf = [0; 96];
+        let mut out = ByteWriter::new(&mut buf);
+        let _ = write!(out, "{:016x}: ", line);
+        for (i, b) in bytes.iter().enumerate() {
+            let _ = if i < n {
+                write!(out, "{:02x} ", b)
+            } else {
+                out.write_str("   ")
+            };
+        }
+        let _ = out.write_str(" |");
+        for &b in &bytes[..n] {
+            let c = if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            };
+            let _ = out.write_char(c);
+        }
+        println!("{}|", out.as_str());
+    }
+}
+
+// Physical memory. Identical to hexdump() while the kernel runs
+// identity mapped; a hexdump_virt() that walks a page table can sit
+// next to it once there are page tables.
+/// # Safety
+///
+/// As hexdump().
+pub unsafe fn hexdump_phys(pa: usize, len: usize) {
+    hexdump(pa, len)
+}
@@ -6,7 +6,7 @@
 /// This entry function is loaded at address 0x80000000 since it is a .text section
 /// and the linker lays those out first. This entry function's job is to set up the
 /// kernel stack so we have some space to work. Refer to src/param.rs for general
-/// memory layout. The kernel stack depends on the number of harts on the h/w (or qemu).
+/// memory layout. Each hart gets a KSTACK_SIZE stack, indexed by its mhartid.
 /// We mostly reference this from `xv6-riscv/kernel/entry.S` and follow their memory layout.
 /// TODO: We have not yet implemented the trampoline mechanism.
 /// But notice the use of inline `global_asm!`, and that the `_start` function is 
@@ -15,6 +15,20 @@
 // https://dev-doc.rust-lang.org/beta/unstable-book/library-features/global-asm.html
 use core::arch::global_asm;
 
+use crate::param::{KSTACK_SIZE, NHART};
+
+// Stacks are carved out of the _stack_start.._stack_end region
+// that kernel.ld reserves after .bss (0x80000 bytes, starting
+// 16 byte aligned).
+const STACK_REGION_SIZE: usize = 0x80000;
+
+// The asm below takes its stride straight from KSTACK_SIZE; make
+// sure that stride keeps sp aligned and every hart fits.
+const _: () = {
+    assert!(KSTACK_SIZE.is_multiple_of(16));
+    assert!(KSTACK_SIZE * NHART <= STACK_REGION_SIZE);
+};
+
 global_asm!(
     r#"
     .section .text
@@ -28,25 +42,24 @@ global_asm!(
         la gp, _global_pointer
     .option pop
 
-        # Set up stack per # of hart ids
-        li t0, 0x0
-        li t0, 0x1000 # = 4096
-        li t1, 0x2 # For param::NHART == 2...this is unstable.
-        mulw t0, t0, t1 # 4096 * NHART
-        la sp, end
-        add sp, sp, t0 # Setup stack ptr at offset + end of .bss
-
-        # Add 4k guard page per hart
-        li a0, 0x1000
+        # Each hart gets its own stack, growing down from
+        # sp = stack_base + (hartid + 1) * KSTACK_SIZE.
         csrr a1, mhartid
         addi a1, a1, 1
-        mulw a0, a0, a1
-        add sp, sp, a0
-        
+        li t0, {kstack_size}
+        mul t0, t0, a1
+        # kernel.ld aligns _stack_start to 16.
+        la sp, _stack_start
+        add sp, sp, t0
+
+        # Keep the hartid in tp so Rust code can always find it.
+        csrr tp, mhartid
+
         # Jump to _start in src/main.rs
         call _start
     spin:
         # wfi
         j spin
-    "#
+    "#,
+    kstack_size = const KSTACK_SIZE,
 );
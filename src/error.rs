@@ -0,0 +1,60 @@
+//! Kernel wide error type.
+// Subsystems keep their own detailed errors (vm::VmError, ...) and
+// convert into KernelError at their boundary, so callers further up
+// and the syscall layer only ever deal with one type.
+use core::fmt;
+
+use crate::vm::VmError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelError {
+    OutOfMemory,
+    NotFound,
+    AlreadyExists,
+    InvalidArgument,
+    WouldBlock,
+    Faulted,
+}
+
+pub type KernelResult<T> = Result<T, KernelError>;
+
+impl KernelError {
+    // Negative errno style value a syscall returns in a0.
+    // Numbers follow Linux so user code has familiar values.
+    pub const fn to_errno(self) -> isize {
+        match self {
+            KernelError::OutOfMemory => -12,     // ENOMEM
+            KernelError::NotFound => -2,         // ENOENT
+            KernelError::AlreadyExists => -17,   // EEXIST
+            KernelError::InvalidArgument => -22, // EINVAL
+            KernelError::WouldBlock => -11,      // EAGAIN
+            KernelError::Faulted => -14,         // EFAULT
+        }
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            KernelError::OutOfMemory => "out of memory",
+            KernelError::NotFound => "not found",
+            KernelError::AlreadyExists => "already exists",
+            KernelError::InvalidArgument => "invalid argument",
+            KernelError::WouldBlock => "operation would block",
+            KernelError::Faulted => "bad address",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl From<VmError> for KernelError {
+    fn from(e: VmError) -> Self {
+        match e {
+            VmError::OutOfMemory => KernelError::OutOfMemory,
+            VmError::AlreadyMapped => KernelError::AlreadyExists,
+            VmError::Misaligned | VmError::BadFlags => KernelError::InvalidArgument,
+            VmError::NotMapped | VmError::OutOfRange => KernelError::Faulted,
+            VmError::ShootdownTimeout => KernelError::WouldBlock,
+        }
+    }
+}
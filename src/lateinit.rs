@@ -0,0 +1,165 @@
+//! Globals that are set once during boot.
+//!
+//! `Lazy` runs its constructor on first access instead:
+//!
+//! ```ignore
+//! use crate::lateinit::Lazy;
+//!
+//! static TABLE: Lazy<Mutex<[u8; 16]>> = Lazy::new(|| Mutex::new([0; 16]));
+//!
+//! fn touch() {
+//!     TABLE.lock()[0] = 1; // constructed here on first use
+//! }
+//! ```
+// Many drivers can't be built in a const context, which would
+// otherwise mean a `Mutex<Option<T>>` and an `.unwrap()` at every
+// use site. A `LateInit` is written exactly once by `init()` and is
+// read only afterwards, so `get()` needs no lock.
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::*;
+
+use crate::cpu::smp_started;
+
+const UNINIT: u8 = 0;
+const INITING: u8 = 1;
+const READY: u8 = 2;
+
+pub struct LateInit<T> {
+    state: AtomicU8,
+    inner: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for LateInit<T> {}
+
+impl<T> Default for LateInit<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> LateInit<T> {
+    pub const fn new() -> Self {
+        LateInit {
+            state: AtomicU8::new(UNINIT),
+            inner: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    // Store the value. Panics if called more than once.
+    pub fn init(&self, value: T) {
+        if self
+            .state
+            .compare_exchange(UNINIT, INITING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            panic!("LateInit initialized twice");
+        }
+        unsafe {
+            (*self.inner.get()).write(value);
+        }
+        // Release so the write above is visible to any hart
+        // that observes READY.
+        self.state.store(READY, Ordering::Release);
+    }
+
+    pub fn is_init(&self) -> bool {
+        self.state.load(Ordering::Acquire) == READY
+    }
+
+    // Panics with a clear message instead of handing out garbage
+    // if someone reaches for the value too early.
+    pub fn get(&self) -> &T {
+        if !self.is_init() {
+            panic!("LateInit used before init");
+        }
+        unsafe { (*self.inner.get()).assume_init_ref() }
+    }
+
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(self.get())
+    }
+}
+
+// Like LateInit, but initialized by `F` the first time anyone
+// dereferences it. Harts racing the first access agree on a single
+// winner through `state`; the rest spin until the value is READY.
+pub struct Lazy<T, F = fn() -> T> {
+    state: AtomicU8,
+    inner: UnsafeCell<MaybeUninit<T>>,
+    init: UnsafeCell<Option<F>>,
+}
+
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Lazy {
+            state: AtomicU8::new(UNINIT),
+            inner: UnsafeCell::new(MaybeUninit::uninit()),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+
+    pub fn force(&self) -> &T {
+        if self.state.load(Ordering::Acquire) == READY {
+            return unsafe { (*self.inner.get()).assume_init_ref() };
+        }
+        match self
+            .state
+            .compare_exchange(UNINIT, INITING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                // We won, nobody else touches `init` or `inner` until READY.
+                let f = unsafe { (*self.init.get()).take() }.unwrap();
+                unsafe {
+                    (*self.inner.get()).write(f());
+                }
+                self.state.store(READY, Ordering::Release);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != READY {
+                    spin_loop();
+                }
+            }
+        }
+        unsafe { (*self.inner.get()).assume_init_ref() }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.force()
+    }
+}
+
+// Mutable global for the single threaded part of boot, before
+// cpu::smp_start() releases the other harts. Cheaper than a Mutex
+// and, unlike a `static mut`, enforces the "hart 0 only" invariant:
+// get_mut() panics once SMP has started.
+pub struct BootCell<T> {
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for BootCell<T> {}
+
+impl<T> BootCell<T> {
+    pub const fn new(value: T) -> Self {
+        BootCell {
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The caller must not hold another reference obtained
+    /// from this cell; only one hart runs, but it can still alias.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_mut(&self) -> &mut T {
+        assert!(!smp_started(), "BootCell accessed after smp_start()");
+        &mut *self.inner.get()
+    }
+}
@@ -1,14 +1,184 @@
 //! Logging and printing macros
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(debug_assertions)]
+use crate::param::NHART;
+use crate::param::{EARLY_LOG_SIZE, PRINT_BUF_SIZE};
+use crate::spinlock::{Mutex, MutexGuard};
+use crate::uart;
 
 macro_rules! print
 {
     ($($args:tt)+) => ({
-        use core::fmt::Write;
-        use crate::uart;
-        let _ = write!(uart::WRITER.lock(), $($args)+);
+        crate::log::_print(format_args!($($args)+));
     });
 }
 
+// Per hart "currently printing" flags. If a trap or panic fires
+// while this hart holds the console lock and then tries to print,
+// spinning on WRITER would deadlock the hart against itself.
+// Indexed by tp, which _entry sets before any Rust runs; the
+// lookups are bounds checked anyway so a bad tp can't panic here.
+#[cfg(debug_assertions)]
+static IN_CONSOLE: [AtomicBool; NHART] = [const { AtomicBool::new(false) }; NHART];
+
+// Anything printed before the UART is up lands here instead of
+// being lost, and is replayed by flush_early_log(). Once full the
+// oldest bytes are overwritten.
+struct EarlyLog {
+    buf: [u8; EARLY_LOG_SIZE],
+    head: usize, // next byte to write
+    len: usize,
+}
+
+impl Write for EarlyLog {
+    fn write_str(&mut self, out: &str) -> core::fmt::Result {
+        for c in out.bytes() {
+            self.buf[self.head] = c;
+            self.head = (self.head + 1) % EARLY_LOG_SIZE;
+            self.len = core::cmp::min(self.len + 1, EARLY_LOG_SIZE);
+        }
+        Ok(())
+    }
+}
+
+static EARLY_LOG: Mutex<EarlyLog> = Mutex::new(EarlyLog {
+    buf: [0; EARLY_LOG_SIZE],
+    head: 0,
+    len: 0,
+});
+
+// Register this module's locks for `dump_lock_stats()`.
+#[cfg(feature = "lock-stats")]
+pub fn track_locks() {
+    EARLY_LOG.track("early_log");
+}
+
+// Flips once, after the early log has been drained. Until then
+// print! goes to EARLY_LOG, afterwards straight to the UART.
+// Stored with Release and loaded with Acquire, so a hart that sees
+// it set also sees the initialized WRITER and the drained buffer.
+static CONSOLE_READY: AtomicBool = AtomicBool::new(false);
+
+// Drain everything buffered before UART init out to the UART,
+// then switch print! over to it. Call right after uart::Uart::init().
+pub fn flush_early_log() {
+    let mut early = EARLY_LOG.lock();
+    let mut uart = uart::WRITER.get().lock();
+    let start = (early.head + EARLY_LOG_SIZE - early.len) % EARLY_LOG_SIZE;
+    for i in 0..early.len {
+        uart.put(early.buf[(start + i) % EARLY_LOG_SIZE]);
+    }
+    early.len = 0;
+    // Still holding EARLY_LOG, so nobody can slip a write into the
+    // buffer between the drain and the switch.
+    CONSOLE_READY.store(true, Ordering::Release);
+}
+
+// The console for the panic path, whoever holds it. The lock may
+// belong to a hart that died mid-print (maybe this one, if the
+// panic came from inside print!), and waiting on it would hang the
+// panic report. The order matters:
+//   1. cpu::claim_panic(), so only one hart gets here;
+//   2. cpu::stop_other_harts(), so the holder can't resume and
+//      write over us;
+//   3. console_force_acquire();
+//   4. print through the guard, then park.
+// At worst the interrupted line is cut short. The UART must be up.
+pub fn console_force_acquire() -> MutexGuard<'static, uart::Uart> {
+    let console = uart::WRITER.get();
+    // Safety: per the above, no guard for it is in use anymore.
+    unsafe { console.force_unlock() };
+    console.lock()
+}
+
+// fmt::Write into a caller provided buffer, e.g. to build a line on
+// the stack and then emit it in one go. Output that doesn't fit is
+// cut off at a char boundary and the write reports fmt::Error.
+pub struct ByteWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> ByteWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        ByteWriter { buf, len: 0 }
+    }
+
+    // Everything written so far.
+    pub fn as_str(&self) -> &str {
+        // Only whole chars of &strs are ever copied in.
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+}
+
+impl Write for ByteWriter<'_> {
+    fn write_str(&mut self, out: &str) -> core::fmt::Result {
+        let room = self.buf.len() - self.len;
+        let mut n = core::cmp::min(room, out.len());
+        while !out.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&out.as_bytes()[..n]);
+        self.len += n;
+        if n < out.len() {
+            Err(core::fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn emit(args: core::fmt::Arguments) {
+    if !CONSOLE_READY.load(Ordering::Acquire) {
+        let mut early = EARLY_LOG.lock();
+        // Recheck under the lock: flush_early_log() may have run
+        // while we waited, and nobody would drain us afterwards.
+        if !CONSOLE_READY.load(Ordering::Acquire) {
+            let _ = early.write_fmt(args);
+            return;
+        }
+    }
+
+    // Format first so the console lock is only held for the
+    // byte copy. Anything too long for the buffer is streamed
+    // under the lock as before.
+    let mut buf = [0; PRINT_BUF_SIZE];
+    let mut line = ByteWriter::new(&mut buf);
+    if line.write_fmt(args).is_ok() {
+        let _ = uart::WRITER.get().lock().write_str(line.as_str());
+    } else {
+        let _ = uart::WRITER.get().lock().write_fmt(args);
+    }
+}
+
+// Backs the print! macro. Not meant to be called directly.
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    #[cfg(debug_assertions)]
+    let hart = crate::riscv::read_tp() as usize;
+    #[cfg(debug_assertions)]
+    if IN_CONSOLE
+        .get(hart)
+        .is_some_and(|f| f.swap(true, Ordering::Acquire))
+    {
+        // We already hold the lock further up this hart's stack,
+        // so write straight to the device instead.
+        let mut raw = unsafe { uart::Uart::steal() };
+        let _ = raw.write_str("[reentrant] ");
+        let _ = raw.write_fmt(args);
+        return;
+    }
+
+    emit(args);
+
+    #[cfg(debug_assertions)]
+    if let Some(f) = IN_CONSOLE.get(hart) {
+        f.store(false, Ordering::Release);
+    }
+}
+
 macro_rules! println
 {
     () => ({
@@ -29,7 +199,6 @@ pub enum LogSeverity {
     Error,
 }
 
-
 // use as `log::log!(Warning, "This is a test of the warning logging!");`
 // in a while that has
 // ```
@@ -52,7 +221,7 @@ macro_rules! log
     (Error, $fmt:expr) => ({
 	print!(concat!("[ERROR] ", $fmt, "\r\n"))
     });
-    
+
     (Debug, $fmt:expr, $($args:tt)+) => ({
 	print!(concat!("[DEBUG]", $fmt, "\r\n"), $($args)+)
     });
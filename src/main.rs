@@ -2,23 +2,51 @@
 #![no_std]
 #![no_main]
 
+use core::fmt::Write;
 use core::panic::PanicInfo;
 
+pub mod bitmap;
+pub mod boot;
+pub mod clock;
+pub mod cpu;
+pub mod debug;
 pub mod entry;
+pub mod error;
+pub mod lateinit;
 #[macro_use]
 pub mod log;
+pub mod mmio;
 pub mod param;
+pub mod plic;
+pub mod proc;
 pub mod riscv;
 pub mod spinlock;
 pub mod timervec;
 pub mod uart;
+pub mod vm;
 use log::*;
 use riscv::*;
 
 // The never type "!" means diverging function (never returns).
+// Only the first hart to panic reports; anyone after it (including
+// a second panic on the same hart, say from inside print!) just
+// parks, so the message comes out whole. See
+// log::console_force_acquire() for why the steps go in this order.
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    if !cpu::claim_panic() {
+        cpu::park()
+    }
+    cpu::stop_other_harts();
+    if uart::WRITER.is_init() {
+        let mut console = log::console_force_acquire();
+        let _ = write!(console, "hart {} {}\r\n", read_tp(), info);
+        let _ = write!(console, "sstatus: {:?}\r\n", Sstatus(read_sstatus()));
+    } else {
+        // Too early for the UART, leave it in the early log.
+        println!("hart {} {}", read_tp(), info);
+    }
+    cpu::park()
 }
 
 // Sets up the core local interrupt controller on each hart.
@@ -28,7 +56,7 @@ fn panic(_info: &PanicInfo) -> ! {
 fn timerinit() {
     let clint = param::CLINT_BASE;
     let hartid = read_mhartid();
-    let interval = 1000000; // <- # no. cycles ~ 1/10 sec in qemu.
+    let interval = param::TIMEBASE_HZ / param::TICK_HZ; // mtime ticks per timer interrupt
     write_clint(hartid, clint, interval);
     
     let mut clint = timervec::Clint::new(clint);
@@ -37,7 +65,7 @@ fn timerinit() {
     // Set the machine trap vector to hold fn ptr to timervec:
     // https://stackoverflow.com/questions/50717928/what-is-the-difference-between-mscratch-and-mtvec-registers
     let timervec_fn = timervec::timervec as *const (); 
-    write_mtvec(timervec_fn);
+    write_mtvec(timervec_fn, TvecMode::Direct);
     
     // Enable machine mode interrupts with mstatus reg.
     write_mstatus(read_mstatus() | MSTATUS_MIE);
@@ -66,6 +94,8 @@ pub extern "C" fn _start() {
     let mut ms = read_mstatus();
     ms &= !MSTATUS_MPP_MASK;
     ms |= MSTATUS_MPP_S; 
+    // Turn the FPU on (FS=Initial) so FP instrs don't trap.
+    ms = (ms & !MSTATUS_FS_MASK) | FsState::Initial.bits();
     write_mstatus(ms);
 
     // Set machine exception prog counter to 
@@ -88,12 +118,17 @@ pub extern "C" fn _start() {
     write_pmpaddr0(0x3fffffffffffff_u64); // Prayers that ULL == u64
     write_pmpcfg0(0xf);
 
+    // Let sup mode read the cycle, time and instret counters.
+    write_mcounteren(MCOUNTEREN_CY | MCOUNTEREN_TM | MCOUNTEREN_IR);
+
     // Get interrupts from clock, handled by timerinit().
     timerinit();
 
-    // Store each hart's hartid in its tp reg for identification.
-    let hartid = read_mhartid();
-    write_tp(hartid);
+    // misa is machine mode only, keep a copy for later.
+    cache_misa();
+
+    // Each hart's hartid is already in its tp reg for
+    // identification, _entry put it there.
 
     // Now return to sup mode and jump to main().
     call_mret();
@@ -107,12 +142,42 @@ pub extern "C" fn _start() {
 fn main() -> ! {
     // We only bootstrap on hart0.
     let id = riscv::read_tp();
+    // All of this hart's setup so far happened in _start (timer
+    // scratch, mscratch); it's published along with the online bit.
+    cpu::mark_online();
     if id == 0 {
         uart::Uart::init();
+        log::flush_early_log();
+        boot::advance_stage(boot::BootStage::Uart);
+        #[cfg(feature = "lock-stats")]
+        {
+            uart::WRITER.get().track("uart");
+            log::track_locks();
+        }
         println!("{}", param::BANNER);
         log!(Info, "Bootstrapping on hart0...");
+        log!(Info, "ISA: {}", IsaString);
+        clock::init();
+        plic::init();
+        // Harts that are slow to come up may be missing here.
+        log!(Info, "Harts online: {:#b}", cpu::online_mask());
+        cpu::smp_start();
     } else {
+        cpu::spin_then_wfi(cpu::smp_started);
+    }
+    // Every hart, now that hart 0 has picked the controller.
+    plic::controller().init_hart();
+
+    loop {
+        if cpu::halt_requested() {
+            cpu::park();
+        }
+        vm::handle_tlb_shootdown();
+        #[cfg(feature = "heartbeat")]
+        cpu::heartbeat();
+        #[cfg(feature = "watchdog")]
+        if id == 0 {
+            timervec::watchdog_check();
+        }
     }
-
-    loop {}
 }
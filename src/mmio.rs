@@ -0,0 +1,32 @@
+//! Volatile helpers for memory mapped I/O.
+// Plain copies and fills (core::ptr::copy, write_bytes, memcpy) may
+// be merged, reordered, widened or elided by the compiler, which is
+// fine for RAM but not for device registers or memory a device is
+// reading via DMA. These touch every byte with its own volatile
+// access, in order. Don't use them for normal memory, they're slow.
+
+// Set `len` bytes starting at `base` to `byte`, e.g. zeroing a
+// virtio descriptor table before handing it to the device.
+/// # Safety
+///
+/// [base, base + len) must be valid, writable device or
+/// DMA memory that nothing else is using concurrently.
+pub unsafe fn volatile_fill(base: usize, byte: u8, len: usize) {
+    let ptr = base as *mut u8;
+    for i in 0..len {
+        ptr.add(i).write_volatile(byte);
+    }
+}
+
+// Copy `len` bytes from `src` to `dst`, front to back.
+/// # Safety
+///
+/// Both ranges must be valid for the access and
+/// must not overlap.
+pub unsafe fn volatile_copy(dst: usize, src: usize, len: usize) {
+    let dst = dst as *mut u8;
+    let src = src as *const u8;
+    for i in 0..len {
+        dst.add(i).write_volatile(src.add(i).read_volatile());
+    }
+}
@@ -28,10 +28,110 @@
 // Memlayout params
 pub const UART_BASE: usize = 0x10000000;
 pub const CLINT_BASE: usize = 0x2000000;
+// CLINT msip words (machine software interrupt), one u32 per hart.
+// Not to be confused with the separate ACLINT SSWI block.
+pub const MSIP_BASE: usize = CLINT_BASE;
+pub const ACLINT_SSWI_BASE: usize = 0x2F00000;
+pub const PLIC_BASE: usize = 0xc000000;
+// The AIA interrupt controllers. QEMU maps these instead of the
+// PLIC when started with aia=aplic; nothing drives them yet.
+pub const APLIC_M_BASE: usize = 0xc000000;
+pub const APLIC_S_BASE: usize = 0xd000000;
 
 
+// Paging
+pub const PAGE_SIZE: usize = 4096;
+
+// A paging scheme: `levels` 9 bit page table indices over a
+// 12 bit page offset, e.g. Sv39 is vpn[2], vpn[1], vpn[0], offset.
+pub struct PagingMode {
+    pub levels: usize,
+    pub va_bits: u32,
+    // MODE field value for satp.
+    pub satp_mode: u64,
+}
+
+pub const SV39: PagingMode = PagingMode {
+    levels: 3,
+    va_bits: 39,
+    satp_mode: 8,
+};
+
+pub const SV48: PagingMode = PagingMode {
+    levels: 4,
+    va_bits: 48,
+    satp_mode: 9,
+};
+
+// Chosen by cargo feature: `sv39` (default) or `sv48`, exactly one
+// of them. See src/vm.rs for the address types.
+#[cfg(not(any(feature = "sv39", feature = "sv48")))]
+compile_error!("enable one of the `sv39` or `sv48` features");
+#[cfg(all(feature = "sv39", feature = "sv48"))]
+compile_error!("`sv39` and `sv48` are mutually exclusive; build sv48 with --no-default-features");
+#[cfg(feature = "sv39")]
+pub const PAGING: PagingMode = SV39;
+#[cfg(feature = "sv48")]
+pub const PAGING: PagingMode = SV48;
+
+pub const VA_BITS: u32 = PAGING.va_bits;
+
+// One past the highest usable virtual address. Sv39 allows
+// 1 << 39, but we stay one bit lower (as xv6 does) so we never
+// have to sign extend addresses with bit 38 set. Likewise for Sv48.
+pub const MAXVA: usize = 1 << (VA_BITS - 1);
+// Trap entry/exit code, mapped at the same top page in the
+// kernel and every user address space.
+pub const TRAMPOLINE: usize = MAXVA - PAGE_SIZE;
+// Per process trap frame, the page just below the trampoline.
+pub const TRAPFRAME: usize = TRAMPOLINE - PAGE_SIZE;
+
+// Being off by a page here sends the trampoline jump into garbage.
+const _: () = {
+    assert!(TRAMPOLINE < MAXVA);
+    assert!(TRAMPOLINE.is_multiple_of(PAGE_SIZE));
+    assert!(TRAPFRAME == TRAMPOLINE - PAGE_SIZE);
+    assert!(TRAPFRAME.is_multiple_of(PAGE_SIZE));
+};
+
+// Anything at or above 1 << 38 (Sv39) / 1 << 47 (Sv48) would need
+// sign extending, so MAXVA must not go past it. Spelled out rather
+// than derived from VA_BITS, so a wrong PagingMode table trips it.
+#[cfg(feature = "sv39")]
+const _: () = assert!(MAXVA <= 1 << 38);
+#[cfg(feature = "sv48")]
+const _: () = assert!(MAXVA <= 1 << 47);
+
 // Run parameters
 pub const NHART: usize = 2;
+// Max number of processes.
+pub const NPROC: usize = 64;
+// Pids count up to here, then wrap around. A multiple of 64, the
+// pid pool is a bitmap of that many bits (see src/proc.rs).
+pub const PID_MAX: usize = 32768;
+// Per hart boot stack, see src/entry.rs.
+pub const KSTACK_SIZE: usize = 4096 * 4;
+// Frequency of the time CSR / CLINT mtime on the qemu virt machine.
+pub const TIMEBASE_HZ: u64 = 10_000_000;
+// Timer interrupts per second.
+pub const TICK_HZ: u64 = 10;
+
+// How often an idle hart reports in with the `heartbeat` feature.
+#[cfg(feature = "heartbeat")]
+pub const HEARTBEAT_SECS: u64 = 5;
+// How long a hart may go without a timer tick before the
+// `watchdog` feature calls it wedged.
+#[cfg(feature = "watchdog")]
+pub const WATCHDOG_SECS: u64 = 2;
+
+// Bytes of output buffered before the UART is initialized.
+pub const EARLY_LOG_SIZE: usize = 4096;
+// Stack buffer print! formats into before taking the console lock.
+pub const PRINT_BUF_SIZE: usize = 256;
+
+// Max number of locks tracked by the `lock-stats` feature.
+#[cfg(feature = "lock-stats")]
+pub const NLOCKSTATS: usize = 32;
 
 
 // Unnecessary.
@@ -0,0 +1,105 @@
+//! External interrupt controllers.
+// The virt machine comes with either a PLIC or, with aia=aplic, an
+// APLIC. The trap path only needs to claim and complete interrupts
+// and drivers only need to switch their line on, so both go through
+// the InterruptController picked at boot, the same way the clock does.
+use crate::lateinit::LateInit;
+use crate::param::PLIC_BASE;
+use crate::riscv::read_tp;
+
+pub trait InterruptController: Sync {
+    // Per hart setup, once on every hart before it takes interrupts.
+    fn init_hart(&self);
+    // Route `irq` to this hart's supervisor context.
+    fn enable(&self, irq: u32);
+    fn disable(&self, irq: u32);
+    // 0 masks the source entirely; higher wins.
+    fn set_priority(&self, irq: u32, priority: u32);
+    // Highest priority pending interrupt for this hart, if any.
+    // It stays masked until complete() is called with it.
+    fn claim(&self) -> Option<u32>;
+    fn complete(&self, irq: u32);
+}
+
+// SiFive style PLIC, see the register map in qemu's hw/intc/sifive_plic.c.
+// QEMU gives each hart an M mode context (2 * hart) and an S mode
+// context (2 * hart + 1); we only ever use the latter.
+pub struct Plic {
+    base: usize,
+}
+
+impl Plic {
+    pub const fn new(base: usize) -> Self {
+        Plic { base }
+    }
+
+    fn context() -> usize {
+        2 * read_tp() as usize + 1
+    }
+
+    fn priority(&self, irq: u32) -> *mut u32 {
+        (self.base + 4 * irq as usize) as *mut u32
+    }
+
+    // Enable bits for this hart, 32 sources per word.
+    fn enable_word(&self, irq: u32) -> *mut u32 {
+        (self.base + 0x2000 + 0x80 * Self::context() + 4 * (irq as usize / 32)) as *mut u32
+    }
+
+    fn threshold(&self) -> *mut u32 {
+        (self.base + 0x200000 + 0x1000 * Self::context()) as *mut u32
+    }
+
+    fn claim_reg(&self) -> *mut u32 {
+        (self.base + 0x200004 + 0x1000 * Self::context()) as *mut u32
+    }
+}
+
+impl InterruptController for Plic {
+    // Let every priority through on this hart.
+    fn init_hart(&self) {
+        unsafe { self.threshold().write_volatile(0) }
+    }
+
+    // The enable words are per context, so only this hart
+    // writes them and the read-modify-write is safe.
+    fn enable(&self, irq: u32) {
+        let reg = self.enable_word(irq);
+        unsafe { reg.write_volatile(reg.read_volatile() | 1 << (irq % 32)) }
+    }
+
+    fn disable(&self, irq: u32) {
+        let reg = self.enable_word(irq);
+        unsafe { reg.write_volatile(reg.read_volatile() & !(1 << (irq % 32))) }
+    }
+
+    fn set_priority(&self, irq: u32, priority: u32) {
+        unsafe { self.priority(irq).write_volatile(priority) }
+    }
+
+    // Source 0 doesn't exist, the PLIC reads it back for "nothing".
+    fn claim(&self) -> Option<u32> {
+        match unsafe { self.claim_reg().read_volatile() } {
+            0 => None,
+            irq => Some(irq),
+        }
+    }
+
+    fn complete(&self, irq: u32) {
+        unsafe { self.claim_reg().write_volatile(irq) }
+    }
+}
+
+pub static PLIC: Plic = Plic::new(PLIC_BASE);
+
+static CONTROLLER: LateInit<&'static dyn InterruptController> = LateInit::new();
+
+// Pick the interrupt controller. Only the PLIC has a driver so far;
+// an APLIC one would go here, keyed off what the platform has.
+pub fn init() {
+    CONTROLLER.init(&PLIC);
+}
+
+pub fn controller() -> &'static dyn InterruptController {
+    *CONTROLLER.get()
+}
@@ -0,0 +1,38 @@
+//! Processes
+// Only pid allocation so far; the process table comes later.
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::bitmap::BitmapAllocator;
+use crate::param::PID_MAX;
+
+pub type Pid = u32;
+
+// Next pid to try. Counts up and eventually wraps.
+static NEXT_PID: AtomicU32 = AtomicU32::new(1);
+// Pids currently handed out, one bit each.
+static LIVE_PIDS: BitmapAllocator<{ PID_MAX / 64 }> = BitmapAllocator::new();
+const _: () = assert!(PID_MAX.is_multiple_of(64));
+
+// Hand out a pid that no live process has. Pids increase
+// monotonically until they pass PID_MAX; after that we wrap,
+// skipping 0 and anything still in use. None once every pid is
+// live.
+pub fn next_pid() -> Option<Pid> {
+    for _ in 0..PID_MAX {
+        let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed) as usize % PID_MAX;
+        if pid != 0 && LIVE_PIDS.try_claim(pid) {
+            return Some(pid as Pid);
+        }
+    }
+    None
+}
+
+// Return a pid once its process has been reaped.
+pub fn free_pid(pid: Pid) {
+    assert!(
+        (pid as usize) < PID_MAX && LIVE_PIDS.is_allocated(pid as usize),
+        "free_pid: pid {} not live",
+        pid
+    );
+    LIVE_PIDS.free(pid as usize);
+}
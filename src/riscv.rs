@@ -2,30 +2,148 @@
 // All referenced from xv6-riscv/kernel/riscv.h
 
 use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::param::MSIP_BASE;
 
 // MPP := Machine previous protection mode.
 pub const MSTATUS_MPP_MASK: u64 = 3 << 11; // Mask for bit tricks
 pub const MSTATUS_MPP_M: u64 = 3 << 11; // Machine
 pub const MSTATUS_MPP_S: u64 = 1 << 11; // Supervisor
 pub const MSTATUS_MPP_U: u64 = 0 << 11; // User
+pub const MSTATUS_MPIE: u64 = 1 << 7; // Machine Previous Interrupt Enable
 pub const MSTATUS_MIE: u64 = 1 << 3; // machine-mode interrupt enable.
 
 // sstatus := Supervisor status reg.
-pub const SSTATUS_SPP: u64 = 1 << 8;  // Previous mode, 1=Supervisor, 0=User
+pub const SSTATUS_MXR: u64 = 1 << 19; // Make eXecutable Readable
+pub const SSTATUS_SUM: u64 = 1 << 18; // permit Supervisor User Memory access
+pub const SSTATUS_SPP: u64 = 1 << 8; // Previous mode, 1=Supervisor, 0=User
 pub const SSTATUS_SPIE: u64 = 1 << 5; // Supervisor Previous Interrupt Enable
 pub const SSTATUS_UPIE: u64 = 1 << 4; // User Previous Interrupt Enable
-pub const SSTATUS_SIE: u64 = 1 << 1;  // Supervisor Interrupt Enable
-pub const SSTATUS_UIE: u64 = 1 << 0;  // User Interrupt Enable
+pub const SSTATUS_SIE: u64 = 1 << 1; // Supervisor Interrupt Enable
+pub const SSTATUS_UIE: u64 = 1 << 0; // User Interrupt Enable
+
+// FS := floating point unit state, bits 14:13 of mstatus,
+// mirrored in sstatus. While Off every FP instr traps as illegal.
+// Dirty means f0-f31/fcsr changed since the last save; a lazy save
+// scheme only spills FP regs on context switch when Dirty, and
+// resets to Clean after restoring.
+pub const MSTATUS_FS_MASK: u64 = 3 << 13;
+pub const SSTATUS_FS_MASK: u64 = 3 << 13;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsState {
+    Off = 0,
+    Initial = 1,
+    Clean = 2,
+    Dirty = 3,
+}
+
+impl FsState {
+    pub const fn bits(self) -> u64 {
+        (self as u64) << 13
+    }
+
+    pub const fn from_bits(status: u64) -> Self {
+        match (status >> 13) & 3 {
+            0 => FsState::Off,
+            1 => FsState::Initial,
+            2 => FsState::Clean,
+            _ => FsState::Dirty,
+        }
+    }
+}
+
+// sstatus/mstatus values that Debug format as their fields, for
+// trap and panic dumps, e.g.
+//   Sstatus(read_sstatus()) => "SPP=S SIE=0 SPIE=1 SUM=0 MXR=0 FS=Initial"
+#[derive(Clone, Copy)]
+pub struct Sstatus(pub u64);
+#[derive(Clone, Copy)]
+pub struct Mstatus(pub u64);
+
+fn bit(status: u64, mask: u64) -> u8 {
+    (status & mask != 0) as u8
+}
+
+impl fmt::Debug for Sstatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.0;
+        let spp = if s & SSTATUS_SPP != 0 { 'S' } else { 'U' };
+        write!(
+            f,
+            "SPP={} SIE={} SPIE={} SUM={} MXR={} FS={:?}",
+            spp,
+            bit(s, SSTATUS_SIE),
+            bit(s, SSTATUS_SPIE),
+            bit(s, SSTATUS_SUM),
+            bit(s, SSTATUS_MXR),
+            FsState::from_bits(s)
+        )
+    }
+}
+
+impl fmt::Debug for Mstatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.0;
+        let mpp = match s & MSTATUS_MPP_MASK {
+            MSTATUS_MPP_M => 'M',
+            MSTATUS_MPP_S => 'S',
+            MSTATUS_MPP_U => 'U',
+            _ => '?',
+        };
+        write!(
+            f,
+            "MPP={} MIE={} MPIE={} {:?}",
+            mpp,
+            bit(s, MSTATUS_MIE),
+            bit(s, MSTATUS_MPIE),
+            Sstatus(s)
+        )
+    }
+}
 
 // Machine-mode Interrupt Enable
 pub const MIE_MEIE: u64 = 1 << 11; // external
 pub const MIE_MTIE: u64 = 1 << 7; // timer
-pub const MIE_MSIE: u64 = 1 << 3;  // software
+pub const MIE_MSIE: u64 = 1 << 3; // software
 
 // Supervisor Interrupt Enable
 pub const SIE_SEIE: u64 = 1 << 9; // external
 pub const SIE_STIE: u64 = 1 << 5; // timer
 pub const SIE_SSIE: u64 = 1 << 1; // software
+                                  // Pending bits in sip, same layout as sie.
+pub const SIP_SSIP: u64 = 1 << 1;
+
+// scause := supervisor trap cause.
+// Top bit set => interrupt, the rest is the code below.
+pub const SCAUSE_INTERRUPT: u64 = 1 << 63;
+pub const SCAUSE_CODE_MASK: u64 = !SCAUSE_INTERRUPT;
+
+// Exception codes (interrupt bit clear)
+pub const EXC_INSN_MISALIGNED: u64 = 0;
+pub const EXC_INSN_ACCESS_FAULT: u64 = 1;
+pub const EXC_ILLEGAL_INSN: u64 = 2;
+pub const EXC_BREAKPOINT: u64 = 3;
+pub const EXC_LOAD_MISALIGNED: u64 = 4;
+pub const EXC_LOAD_ACCESS_FAULT: u64 = 5;
+pub const EXC_STORE_MISALIGNED: u64 = 6;
+pub const EXC_STORE_ACCESS_FAULT: u64 = 7;
+pub const EXC_ECALL_U: u64 = 8;
+pub const EXC_ECALL_S: u64 = 9;
+pub const EXC_ECALL_M: u64 = 11;
+pub const EXC_INSN_PAGE_FAULT: u64 = 12;
+pub const EXC_LOAD_PAGE_FAULT: u64 = 13;
+pub const EXC_STORE_PAGE_FAULT: u64 = 15;
+
+// Interrupt codes (interrupt bit set)
+pub const IRQ_S_SOFT: u64 = 1;
+pub const IRQ_M_SOFT: u64 = 3;
+pub const IRQ_S_TIMER: u64 = 5;
+pub const IRQ_M_TIMER: u64 = 7;
+pub const IRQ_S_EXT: u64 = 9;
+pub const IRQ_M_EXT: u64 = 11;
 
 // CLINT := Core local interruptor (where the timer is).
 // CLINT_BASE: usize = 0x2000000; // clint is at this location in memlayout.
@@ -35,20 +153,61 @@ pub const SIE_SSIE: u64 = 1 << 1; // software
 // int interval = 1000000; // cycles; about 1/10th second in qemu.
 // *(uint64*)CLINT_MTIMECMP(id) = *(uint64*)CLINT_MTIME + interval;
 
+// CLINT register addresses, the macros above as const fns.
+// msip: u32 per hart, mtimecmp: u64 per hart, one shared mtime.
+pub const fn clint_msip(base: usize, hartid: u64) -> usize {
+    base + 4 * hartid as usize
+}
+
+pub const fn clint_mtimecmp(base: usize, hartid: u64) -> usize {
+    base + 0x4000 + 8 * hartid as usize
+}
+
+pub const fn clint_mtime(base: usize) -> usize {
+    base + 0xBFF8
+}
+
+// Against the SiFive CLINT layout, as qemu implements it.
+const _: () = {
+    assert!(clint_msip(0x2000000, 0) == 0x2000000);
+    assert!(clint_msip(0x2000000, 1) == 0x2000004);
+    assert!(clint_mtimecmp(0x2000000, 0) == 0x2004000);
+    assert!(clint_mtimecmp(0x2000000, 1) == 0x2004008);
+    assert!(clint_mtimecmp(0x2000000, 7) == 0x2004038);
+    assert!(clint_mtime(0x2000000) == 0x200BFF8);
+};
+
 // Need to write a value to the CLINT memory location.
 // This is mmio, as such there are safety concerns:
 //      https://doc.rust-lang.org/std/ptr/fn.write_volatile.html
-// 
+//
 // Generate a machine lvl interrupt by setting mtime to be >= mtimecmp.
+// Schedules the first one `interval` cycles from now.
 pub fn write_clint(hartid: u64, base: usize, interval: u64) {
-    // Ok, treat base addr as a pointer we can write to.
-    let base = (base + 0x4000 + 8 * (hartid as usize)) as *mut u64;
+    let mtimecmp = clint_mtimecmp(base, hartid) as *mut u64;
+    let mtime = clint_mtime(base) as *const u64;
     unsafe {
-        base.write_volatile(base as u64 + 0xBFF8 + interval);
+        mtimecmp.write_volatile(mtime.read_volatile() + interval);
     }
 }
 
+// Raise a machine mode software interrupt on `hartid` by
+// writing 1 to its msip word. Direct route for bare metal,
+// independent of any SBI IPI call.
+pub fn clint_send_ipi(hartid: u64) {
+    let msip = clint_msip(MSIP_BASE, hartid) as *mut u32;
+    unsafe {
+        msip.write_volatile(1);
+    }
+}
 
+// Acknowledge, the receiving hart does this in its handler.
+pub fn clint_clear_ipi(hartid: u64) {
+    let msip = clint_msip(MSIP_BASE, hartid) as *mut u32;
+    unsafe {
+        msip.write_volatile(0);
+    }
+}
 
 // Return id of current hart.
 // the "m" in "mstatus" means machine mode.
@@ -62,6 +221,60 @@ pub fn read_mhartid() -> u64 {
     id
 }
 
+// misa := machine ISA register.
+// Bits 0..25 flag extensions A..Z, the top two bits (MXL)
+// encode the native XLEN. Only readable in machine mode.
+pub fn read_misa() -> u64 {
+    let misa: u64;
+    unsafe {
+        asm!("csrr {}, misa", out(reg) misa);
+    }
+    misa
+}
+
+// Supervisor code can't read misa, so _start stashes it here.
+static MISA: AtomicU64 = AtomicU64::new(0);
+
+// Call from machine mode before dropping to supervisor mode.
+pub fn cache_misa() {
+    MISA.store(read_misa(), Ordering::Relaxed);
+}
+
+// Is single letter extension `c` (e.g. 'A', 'S', 'H') present?
+pub fn has_extension(c: char) -> bool {
+    let c = c.to_ascii_uppercase();
+    if !c.is_ascii_uppercase() {
+        return false;
+    }
+    MISA.load(Ordering::Relaxed) & (1 << (c as u8 - b'A')) != 0
+}
+
+// Native register width from MXL: 1 => 32, 2 => 64, 3 => 128.
+// Returns 0 if misa is unimplemented (reads as zero).
+pub fn xlen() -> u32 {
+    match MISA.load(Ordering::Relaxed) >> 62 {
+        1 => 32,
+        2 => 64,
+        3 => 128,
+        _ => 0,
+    }
+}
+
+// Formats as the usual ISA string, e.g. "rv64acdfimsu".
+pub struct IsaString;
+
+impl fmt::Display for IsaString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rv{}", xlen())?;
+        for c in b'a'..=b'z' {
+            if has_extension(c as char) {
+                write!(f, "{}", c as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 // Read CSR := Control and Status Register mstatus.
 // Refer to chap 9 of riscv isa manual for info on CSRs.
 pub fn read_mstatus() -> u64 {
@@ -87,6 +300,63 @@ pub fn write_mepc(addr: *const ()) {
     }
 }
 
+// sepc := supervisor exception program counter.
+// On a trap it holds the address of the faulting/ecall instr,
+// and sret jumps back to it.
+pub fn read_sepc() -> u64 {
+    let pc: u64;
+    unsafe {
+        asm!("csrr {}, sepc", out(reg) pc);
+    }
+    pc
+}
+
+pub fn write_sepc(addr: u64) {
+    unsafe {
+        asm!("csrw sepc, {}", in(reg) addr);
+    }
+}
+
+// scause := why we trapped, see the SCAUSE_/EXC_/IRQ_ consts.
+pub fn read_scause() -> u64 {
+    let cause: u64;
+    unsafe {
+        asm!("csrr {}, scause", out(reg) cause);
+    }
+    cause
+}
+
+// stval := trap value. For misaligned accesses and page faults
+// the faulting address, for illegal instrs (maybe) the instr bits.
+pub fn read_stval() -> u64 {
+    let val: u64;
+    unsafe {
+        asm!("csrr {}, stval", out(reg) val);
+    }
+    val
+}
+
+// Length in bytes of the instr whose low 16 bits are `parcel`.
+// Low two bits 0b11 => standard 4 byte encoding, anything else
+// is a 2 byte compressed instr.
+pub fn insn_len(parcel: u16) -> u64 {
+    if parcel & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+// Skip the trapping instr so sret doesn't re-execute it.
+// A syscall must do this after dispatch or it loops on the
+// ecall forever. ecall itself is always 4 bytes; pass
+// insn_len() when the instr may be compressed (e.g. c.ebreak).
+pub fn advance_sepc(bytes: u64) -> u64 {
+    let next = read_sepc() + bytes;
+    write_sepc(next);
+    next
+}
+
 pub fn read_sstatus() -> u64 {
     let status: u64;
     unsafe {
@@ -101,7 +371,68 @@ pub fn write_status(status: u64) {
     }
 }
 
-// Enable sup mode interrupt and exception. 
+// Sup mode interrupts on/off. csrs/csrc set and clear the bit in
+// one instruction, so unlike a read-modify-write with write_status
+// a trap can't slip in between and have its sstatus change undone.
+pub fn intr_on() {
+    unsafe {
+        asm!("csrs sstatus, {}", in(reg) SSTATUS_SIE);
+    }
+}
+
+pub fn intr_off() {
+    unsafe {
+        asm!("csrc sstatus, {}", in(reg) SSTATUS_SIE);
+    }
+}
+
+pub fn interrupts_enabled() -> bool {
+    read_sstatus() & SSTATUS_SIE != 0
+}
+
+// SUM: with paging on, sup mode faults on any access to a page
+// with the U bit unless SUM is set. copyin/copyout must either set
+// it around the copy or (preferably) translate the user address by
+// walking the page table and copy through the kernel mapping, which
+// keeps stray kernel derefs of user pointers faulting.
+pub fn set_sum(enable: bool) {
+    // csrs/csrc for the same reason as intr_on().
+    unsafe {
+        if enable {
+            asm!("csrs sstatus, {}", in(reg) SSTATUS_SUM);
+        } else {
+            asm!("csrc sstatus, {}", in(reg) SSTATUS_SUM);
+        }
+    }
+}
+
+// MXR: let loads read pages that are executable but not readable.
+pub fn set_mxr(enable: bool) {
+    // csrs/csrc for the same reason as intr_on().
+    unsafe {
+        if enable {
+            asm!("csrs sstatus, {}", in(reg) SSTATUS_MXR);
+        } else {
+            asm!("csrc sstatus, {}", in(reg) SSTATUS_MXR);
+        }
+    }
+}
+
+// Set the FP unit state from supervisor mode.
+pub fn set_fs(state: FsState) {
+    // Clear then set, rather than a read-modify-write that could
+    // undo a trap's sstatus change.
+    unsafe {
+        asm!("csrc sstatus, {}", in(reg) SSTATUS_FS_MASK);
+        asm!("csrs sstatus, {}", in(reg) state.bits());
+    }
+}
+
+pub fn fs_state() -> FsState {
+    FsState::from_bits(read_sstatus())
+}
+
+// Enable sup mode interrupt and exception.
 pub fn read_sip() -> u64 {
     let x: u64;
     unsafe {
@@ -116,6 +447,13 @@ pub fn write_sip(ire: u64) {
     }
 }
 
+// Clear `mask` in sip. Only SSIP is writable from sup mode.
+pub fn clear_sip(mask: u64) {
+    unsafe {
+        asm!("csrc sip, {}", in(reg) mask);
+    }
+}
+
 pub fn read_sie() -> u64 {
     let x: u64;
     unsafe {
@@ -130,6 +468,22 @@ pub fn write_sie(ire: u64) {
     }
 }
 
+// Clear `mask` in sie and return its old value, in one csrrc so
+// nothing can change sie in between.
+pub fn clear_sie(mask: u64) -> u64 {
+    let old: u64;
+    unsafe {
+        asm!("csrrc {}, sie, {}", out(reg) old, in(reg) mask);
+    }
+    old
+}
+
+pub fn set_sie(mask: u64) {
+    unsafe {
+        asm!("csrs sie, {}", in(reg) mask);
+    }
+}
+
 pub fn read_mie() -> u64 {
     let x: u64;
     unsafe {
@@ -144,7 +498,6 @@ pub fn write_mie(x: u64) {
     }
 }
 
-
 // SATP := supervisor address translation and protection.
 // This is where we hold the page table address.
 // use riscv's sv39 page table scheme.
@@ -160,11 +513,160 @@ pub fn read_satp() -> u64 {
     pt
 }
 
- pub fn write_satp(pt: u64) {
-     unsafe {
-         asm!("csrw satp, {}", in(reg) pt);
-     }
- }
+pub fn write_satp(pt: u64) {
+    unsafe {
+        asm!("csrw satp, {}", in(reg) pt);
+    }
+}
+
+// sfence.vma := flush cached address translations (TLB).
+// With the `tlb-stats` feature each kind of flush is counted,
+// so we can check we aren't flushing more than we need to.
+#[cfg(feature = "tlb-stats")]
+static SFENCE_ALL: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "tlb-stats")]
+static SFENCE_ADDR: AtomicU64 = AtomicU64::new(0);
+
+// Flush every translation for every address space.
+pub fn sfence_vma_all() {
+    #[cfg(feature = "tlb-stats")]
+    SFENCE_ALL.fetch_add(1, Ordering::Relaxed);
+    unsafe {
+        asm!("sfence.vma zero, zero");
+    }
+}
+
+// Flush the translation for `va` in every address space, global
+// (G bit) mappings included. What the kernel wants for its own map.
+pub fn sfence_vma_va(va: usize) {
+    #[cfg(feature = "tlb-stats")]
+    SFENCE_ADDR.fetch_add(1, Ordering::Relaxed);
+    unsafe {
+        asm!("sfence.vma {}, zero", in(reg) va);
+    }
+}
+
+// Flush only the translation for `va` in address space `asid`.
+// With a nonzero rs2 the spec leaves global mappings alone, so
+// don't use this for kernel addresses; see sfence_vma_va().
+pub fn sfence_vma_addr(va: usize, asid: usize) {
+    #[cfg(feature = "tlb-stats")]
+    SFENCE_ADDR.fetch_add(1, Ordering::Relaxed);
+    unsafe {
+        asm!("sfence.vma {}, {}", in(reg) va, in(reg) asid);
+    }
+}
+
+#[cfg(feature = "tlb-stats")]
+#[derive(Clone, Copy)]
+pub struct TlbStats {
+    pub flush_all: u64,
+    pub flush_addr: u64,
+}
+
+#[cfg(feature = "tlb-stats")]
+pub fn tlb_stats() -> TlbStats {
+    TlbStats {
+        flush_all: SFENCE_ALL.load(Ordering::Relaxed),
+        flush_addr: SFENCE_ADDR.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(feature = "tlb-stats")]
+pub fn dump_tlb_stats() {
+    let stats = tlb_stats();
+    println!(
+        "sfence.vma: {} full, {} single address",
+        stats.flush_all, stats.flush_addr
+    );
+}
+
+// Hypervisor (H extension) CSRs, for experimenting only: plain
+// read/write stubs, no nested paging. Accessed by CSR number since
+// the assembler only knows the names with H enabled. Touching them
+// on a hart without H traps, hence the runtime check.
+// hstatus := hypervisor status
+// hgatp := guest physical address translation (stage 2 satp)
+// vsstatus/vsatp := the guest's view of sstatus/satp
+#[cfg(feature = "hypervisor")]
+fn require_h() {
+    assert!(
+        has_extension('H'),
+        "hypervisor CSR access without H extension"
+    );
+}
+
+#[cfg(feature = "hypervisor")]
+pub fn read_hstatus() -> u64 {
+    require_h();
+    let x: u64;
+    unsafe {
+        asm!("csrr {}, 0x600", out(reg) x);
+    }
+    x
+}
+
+#[cfg(feature = "hypervisor")]
+pub fn write_hstatus(x: u64) {
+    require_h();
+    unsafe {
+        asm!("csrw 0x600, {}", in(reg) x);
+    }
+}
+
+#[cfg(feature = "hypervisor")]
+pub fn read_hgatp() -> u64 {
+    require_h();
+    let x: u64;
+    unsafe {
+        asm!("csrr {}, 0x680", out(reg) x);
+    }
+    x
+}
+
+#[cfg(feature = "hypervisor")]
+pub fn write_hgatp(x: u64) {
+    require_h();
+    unsafe {
+        asm!("csrw 0x680, {}", in(reg) x);
+    }
+}
+
+#[cfg(feature = "hypervisor")]
+pub fn read_vsstatus() -> u64 {
+    require_h();
+    let x: u64;
+    unsafe {
+        asm!("csrr {}, 0x200", out(reg) x);
+    }
+    x
+}
+
+#[cfg(feature = "hypervisor")]
+pub fn write_vsstatus(x: u64) {
+    require_h();
+    unsafe {
+        asm!("csrw 0x200, {}", in(reg) x);
+    }
+}
+
+#[cfg(feature = "hypervisor")]
+pub fn read_vsatp() -> u64 {
+    require_h();
+    let x: u64;
+    unsafe {
+        asm!("csrr {}, 0x280", out(reg) x);
+    }
+    x
+}
+
+#[cfg(feature = "hypervisor")]
+pub fn write_vsatp(x: u64) {
+    require_h();
+    unsafe {
+        asm!("csrw 0x280, {}", in(reg) x);
+    }
+}
 
 // medeleg := machine exception delegation (to supervisor mode)
 // mideleg := machine interrupt delegation (to supervisor mode)
@@ -196,7 +698,7 @@ pub fn write_mideleg(mid: u64) {
     }
 }
 
-// pmpaddr := phys mem protection addr. 
+// pmpaddr := phys mem protection addr.
 // Configure to give supervisor mode access to
 // certain parts of memory.
 pub fn write_pmpaddr0(addr: u64) {
@@ -213,7 +715,7 @@ pub fn write_pmpcfg0(addr: u64) {
 
 // Just for curiosity's sake:
 // https://github.com/rust-lang/rust/issues/82753
-// 
+//
 // tp := thread pointer?
 // This way we can query a hart's hartid and store it in tp reg.
 pub fn write_tp(id: u64) {
@@ -230,47 +732,181 @@ pub fn read_tp() -> u64 {
     tp
 }
 
-// Make sure mret has an addr to go to!
-pub fn call_mret() {
+// cycle := cycles since some arbitrary point in the past.
+// Supervisor mode can only read it if mcounteren.CY is set.
+pub fn read_cycle() -> u64 {
+    let cycles: u64;
     unsafe {
-        asm!("mret");
+        asm!("rdcycle {}", out(reg) cycles);
     }
+    cycles
 }
 
-pub fn write_mscratch(scratch: usize) {
+// time := wall clock ticks at param::TIMEBASE_HZ, the same
+// counter as the CLINT mtime register. Needs mcounteren.TM
+// to read from sup mode.
+pub fn read_time() -> u64 {
+    let time: u64;
     unsafe {
-        asm!("csrw mscratch, {}", in(reg) scratch);
+        asm!("rdtime {}", out(reg) time);
     }
+    time
 }
 
-// Give address of timervec address.
-pub fn write_mtvec(addr: *const ()) {
+// instret := instructions retired. Unlike cycle this doesn't move
+// with cache misses or qemu's host scheduling, so it's the better
+// measure of how much work a code path does. Needs mcounteren.IR.
+pub fn read_instret() -> u64 {
+    let n: u64;
     unsafe {
-        asm!("csrw mtvec, {}", in(reg) addr);
+        asm!("rdinstret {}", out(reg) n);
     }
+    n
 }
 
+// Run `f` and report how many cycles it took, for ad hoc profiling.
+// Reading cycle from sup mode relies on mcounteren.CY, which _start
+// sets; without it rdcycle traps as an illegal instruction.
+pub fn measure_cycles<R>(f: impl FnOnce() -> R) -> (R, u64) {
+    let start = read_cycle();
+    let r = f();
+    let end = read_cycle();
+    (r, end.wrapping_sub(start))
+}
 
+// mcounteren := which counters (cycle, time, instret)
+// lower privilege modes may read.
+pub const MCOUNTEREN_CY: u64 = 1 << 0;
+pub const MCOUNTEREN_TM: u64 = 1 << 1;
+pub const MCOUNTEREN_IR: u64 = 1 << 2;
 
+pub fn write_mcounteren(x: u64) {
+    unsafe {
+        asm!("csrw mcounteren, {}", in(reg) x);
+    }
+}
 
+// Stall the hart until an interrupt is pending.
+pub fn wfi() {
+    unsafe {
+        asm!("wfi");
+    }
+}
 
+// Make sure mret has an addr to go to!
+pub fn call_mret() {
+    unsafe {
+        asm!("mret");
+    }
+}
 
+pub fn write_mscratch(scratch: usize) {
+    unsafe {
+        asm!("csrw mscratch, {}", in(reg) scratch);
+    }
+}
 
+// Low two bits of mtvec/stvec. Direct sends every trap to the
+// base; Vectored sends interrupt `cause` to base + 4 * cause (and
+// exceptions still to the base). See TrapVectorTable.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TvecMode {
+    Direct = 0,
+    Vectored = 1,
+}
 
+// The mode lives in the base's low bits, so a misaligned base
+// would quietly change mode instead of faulting.
+fn tvec(addr: *const (), mode: TvecMode) -> usize {
+    debug_assert!(
+        (addr as usize).is_multiple_of(4),
+        "trap vector base {:p} not 4 byte aligned",
+        addr
+    );
+    addr as usize | mode as usize
+}
 
+// Give address of timervec address.
+pub fn write_mtvec(addr: *const (), mode: TvecMode) {
+    unsafe {
+        asm!("csrw mtvec, {}", in(reg) tvec(addr, mode));
+    }
+}
 
+pub fn write_stvec(addr: *const (), mode: TvecMode) {
+    unsafe {
+        asm!("csrw stvec, {}", in(reg) tvec(addr, mode));
+    }
+}
 
+// Make stores to instruction memory visible to this hart's fetches.
+pub fn fence_i() {
+    unsafe {
+        asm!("fence.i");
+    }
+}
 
+// `jal x0, 0`: an entry that hasn't been set just spins in place,
+// which is easier to spot in a debugger than running off into
+// whatever follows.
+const JAL_SELF: u32 = 0x6f;
 
+// Table for TvecMode::Vectored: one 4 byte jump instruction per
+// interrupt cause, entry 0 doubling as the exception entry.
+//
+// jal is pc relative, so the jumps can only be encoded once the
+// table is at its final address. set() just records the handler;
+// install() takes the table by &'static mut, so it can no longer
+// move, and encodes the jumps there.
+#[repr(C, align(4))]
+pub struct TrapVectorTable {
+    code: [u32; 16],
+    handlers: [Option<unsafe extern "C" fn()>; 16],
+}
 
+impl Default for TrapVectorTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
+impl TrapVectorTable {
+    pub const fn new() -> Self {
+        TrapVectorTable {
+            code: [JAL_SELF; 16],
+            handlers: [None; 16],
+        }
+    }
 
+    // Send `cause` to `handler`, once install()ed.
+    pub fn set(&mut self, cause: usize, handler: unsafe extern "C" fn()) {
+        self.handlers[cause] = Some(handler);
+    }
 
+    // Write a `j handler` for each handler set and point this hart's
+    // stvec at the table. Handlers have to be within jal's +-1MiB of
+    // the table, which anything in the kernel image is.
+    pub fn install(&'static mut self) {
+        for (slot, handler) in self.code.iter_mut().zip(self.handlers) {
+            let Some(handler) = handler else { continue };
+            let offset = (handler as usize).wrapping_sub(slot as *const u32 as usize) as isize;
+            assert!(
+                (-(1 << 20)..1 << 20).contains(&offset),
+                "trap handler out of jal range"
+            );
+            let imm = offset as u32;
+            *slot = (imm >> 20 & 1) << 31
+                | (imm >> 1 & 0x3ff) << 21
+                | (imm >> 11 & 1) << 20
+                | (imm >> 12 & 0xff) << 12
+                | JAL_SELF;
+        }
+        fence_i();
+        write_stvec(self.code.as_ptr() as *const (), TvecMode::Vectored);
+    }
+}
 
-
-
-
-
-
-
-
+const _: () = {
+    assert!(core::mem::align_of::<TrapVectorTable>() >= 4);
+    assert!(core::mem::offset_of!(TrapVectorTable, code) == 0);
+};
@@ -6,65 +6,433 @@
 //
 // Opportunity for improvement on locking mechanism.
 use core::cell::UnsafeCell;
+use core::marker::PhantomData;
 use core::sync::atomic::*;
 
-pub struct MutexGuard<'a, T> {
-    mutex: &'a Mutex<T>,
+#[cfg(feature = "lock-stats")]
+use crate::param::NLOCKSTATS;
+#[cfg(debug_assertions)]
+use crate::riscv::read_tp;
+
+pub struct MutexGuard<'a, T, P = SpinPolicy> {
+    mutex: &'a Lock<T, P>,
 }
 
 /// A great Rust thing. Locking a mutex returns
 /// a guard which derefs to the type behind the
 /// mutex, which unlocks when it goes out of scope.
-impl<T> core::ops::Deref for MutexGuard<'_, T> {
+impl<T, P> core::ops::Deref for MutexGuard<'_, T, P> {
     type Target = T;
     fn deref(&self) -> &T {
         unsafe { &*self.mutex.inner.get() }
     }
 }
 
-impl<T> core::ops::DerefMut for MutexGuard<'_, T> {
+impl<T, P> core::ops::DerefMut for MutexGuard<'_, T, P> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.mutex.inner.get() }
     }
 }
 
-impl<T> core::ops::Drop for MutexGuard<'_, T> {
+impl<T, P> core::ops::Drop for MutexGuard<'_, T, P> {
     fn drop(&mut self) {
+        // A guard only exists while the lock is held, so finding it
+        // already unlocked means a force_unlock() raced us or the
+        // lock word got scribbled on. Catch that during bring-up.
+        #[cfg(debug_assertions)]
+        {
+            self.mutex.owner.released();
+            let prev = self.mutex.lock_state.swap(0, Ordering::Release);
+            assert!(
+                prev == 1,
+                "MutexGuard dropped on unlocked mutex (state {})",
+                prev
+            );
+        }
+        #[cfg(not(debug_assertions))]
         self.mutex.lock_state.store(0, Ordering::Release);
     }
 }
 
+// What a waiting hart does each time around the loop in lock()
+// while the lock is still held. `spins` counts the waits so far.
+pub trait LockPolicy {
+    fn wait(spins: u64);
+}
+
+// Spin for the first SPINS waits, then yield the hart on each one
+// after that. SPINS = u32::MAX never yields: pure spinning, for
+// short critical sections and anything taken from interrupt
+// context. Locks held across long operations want a small SPINS.
+pub struct SpinThenYield<const SPINS: u32>;
+
+impl<const SPINS: u32> LockPolicy for SpinThenYield<SPINS> {
+    fn wait(spins: u64) {
+        // SPINS is a constant, so for pure spin locks this folds
+        // away and the wait stays a bare spin_loop().
+        if SPINS == u32::MAX || spins <= SPINS as u64 {
+            core::hint::spin_loop();
+        } else {
+            yield_hart();
+        }
+    }
+}
+
+pub type SpinPolicy = SpinThenYield<{ u32::MAX }>;
+pub type YieldPolicy = SpinThenYield<0>;
+
+// Stands in for the scheduler's yield_proc(). There is no
+// scheduler yet, so yielding locks still spin for now.
+fn yield_hart() {
+    core::hint::spin_loop();
+}
+
 // Simple mutex implementation.
 // 1. Try to acquire mutex for critical section.
-// 2. If unable, spin.
-pub struct Mutex<T> {
+// 2. If unable, wait as the policy P says, then retry.
+//
+// repr(C) so the front of the layout can be relied on when a Mutex
+// sits at a known address (e.g. a shared state page): the u32 lock
+// word at offset 0, then the data at OFFSET_OF_INNER, which is 4
+// rounded up to T's alignment. Only those two offsets are stable.
+// The lock-stats counters and the debug build owner come after the
+// data, so size_of and everything past the data differ between
+// builds; don't size shared memory off a Lock.
+#[repr(C)]
+pub struct Lock<T, P = SpinPolicy> {
     lock_state: AtomicU32, // (0,1) = (unlocked, locked)
-    inner: UnsafeCell<T>, 
+    inner: UnsafeCell<T>,
+    #[cfg(feature = "lock-stats")]
+    counters: LockCounters,
+    #[cfg(debug_assertions)]
+    owner: LockOwner,
+    policy: PhantomData<fn() -> P>,
 }
 
-unsafe impl<T: Send> Sync for Mutex<T> {}
+// The plain spinlock, what almost everything should use. Give a
+// SPINS to spin only that long before yielding, e.g. Mutex<T, 1000>.
+pub type Mutex<T, const SPINS: u32 = { u32::MAX }> = Lock<T, SpinThenYield<SPINS>>;
+
+unsafe impl<T: Send, P> Sync for Lock<T, P> {}
+
+impl<T, P: LockPolicy> Lock<T, P> {
+    pub const OFFSET_OF_INNER: usize = core::mem::offset_of!(Self, inner);
 
-impl<T> Mutex<T> {
     // https://doc.rust-lang.org/reference/const_eval.html
     pub const fn new(value: T) -> Self {
-        Mutex {
+        Lock {
             lock_state: AtomicU32::new(0),
             inner: UnsafeCell::new(value),
+            #[cfg(feature = "lock-stats")]
+            counters: LockCounters::new(),
+            #[cfg(debug_assertions)]
+            owner: LockOwner::new(),
+            policy: PhantomData,
+        }
+    }
+
+    // Starts out held, as a crude gate: e.g. the boot hart keeps
+    // it locked until shared state is ready, then releases it for
+    // the secondary harts. Nobody owns a guard for this initial
+    // hold, so exactly one force_unlock() must eventually open it,
+    // or every lock() spins forever.
+    pub const fn new_locked(value: T) -> Self {
+        Lock {
+            lock_state: AtomicU32::new(1),
+            inner: UnsafeCell::new(value),
+            #[cfg(feature = "lock-stats")]
+            counters: LockCounters::new(),
+            #[cfg(debug_assertions)]
+            owner: LockOwner::new(),
+            policy: PhantomData,
         }
     }
 
-    // Needs to satisfy an atomic swap (acquire)
+    // Release the lock without a guard.
+    /// # Safety
+    ///
+    /// Nobody may be using a guard for this lock, or
+    /// two harts end up inside the critical section at once.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.released();
+        self.lock_state.store(0, Ordering::Release);
+    }
+
+    // Raw pointer to the protected data, bypassing the lock, e.g. to
+    // hand a buffer's address to a device that will DMA into it.
+    /// # Safety
+    ///
+    /// The caller must make sure nothing reading or writing
+    /// through a guard conflicts with what the device is doing, and
+    /// must not turn this into a reference while a guard is live.
+    pub unsafe fn raw_inner(&self) -> *mut T {
+        self.inner.get()
+    }
+
+    // Needs to satisfy an atomic compare and swap (acquire)
     // then a fence so loads and stores aren't reordered until
     // after lock is acquired.
-    pub fn lock(&self) -> MutexGuard<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T, P> {
         // Use Acquire memory order to load lock value.
-        // TODO:
-        // Spin loop improvement.
-        while self.lock_state.swap(1, Ordering::Acquire) == 1 {}
+        // compare_exchange_weak maps straight onto an LR/SC pair
+        // and doesn't write when the lock is already held, unlike swap.
+        // While held, spin on plain loads so waiting harts don't
+        // keep bouncing the cache line around.
+        let mut spins = 0;
+        while self
+            .lock_state
+            .compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.lock_state.load(Ordering::Relaxed) == 1 {
+                spins += 1;
+                #[cfg(debug_assertions)]
+                self.owner.waiting(spins);
+                P::wait(spins);
+            }
+        }
+        #[cfg(feature = "lock-stats")]
+        self.counters.record(spins);
+        #[cfg(debug_assertions)]
+        self.owner.acquired();
         MutexGuard { mutex: self }
     }
 
-    
+    // lock(), but in debug builds `name` is remembered as the holder
+    // so a hart stuck waiting can say which lock it's stuck on.
+    // Compiles down to plain lock() in release builds.
+    pub fn lock_named(&self, name: &'static str) -> MutexGuard<'_, T, P> {
+        let guard = self.lock();
+        #[cfg(debug_assertions)]
+        self.owner.set_name(name);
+        #[cfg(not(debug_assertions))]
+        let _ = name;
+        guard
+    }
+
+    // Snapshot of this lock's contention counters.
+    #[cfg(feature = "lock-stats")]
+    pub fn stats(&self) -> LockStats {
+        self.counters.snapshot()
+    }
+
+    // Add this lock to the global registry under `name`
+    // so it shows up in `dump_lock_stats()`. Only statics
+    // can be tracked, since the registry keeps a reference.
+    // Tracking is opt-in; main() registers the kernel's locks.
+    #[cfg(feature = "lock-stats")]
+    pub fn track(&'static self, name: &'static str) {
+        LOCK_REGISTRY.lock().register(name, &self.counters);
+    }
+}
+
+// Pin the two offsets described above, and nothing more.
+const _: () = {
+    assert!(core::mem::offset_of!(Mutex<u8>, lock_state) == 0);
+    assert!(Mutex::<u8>::OFFSET_OF_INNER == 4);
+    assert!(Mutex::<u32>::OFFSET_OF_INNER == 4);
+    assert!(Mutex::<u64>::OFFSET_OF_INNER == 8);
+    assert!(Mutex::<[u64; 4]>::OFFSET_OF_INNER == 8);
+};
+
+// Who holds a lock, debug builds only, for the diagnostics in
+// lock(). Set by the holder right after acquiring and cleared just
+// before releasing, so a waiter may briefly see "unknown".
+#[cfg(debug_assertions)]
+struct LockOwner {
+    hart: AtomicUsize, // holder's hartid + 1, 0 if unknown
+    // The holder's lock_named() name. There's no atomic fat
+    // pointer, so it's stored as the two halves of the &str.
+    name_ptr: AtomicPtr<u8>,
+    name_len: AtomicUsize,
 }
 
+// Spins before a waiter complains, roughly a second under qemu.
+#[cfg(debug_assertions)]
+const SPIN_WARN: u64 = 1 << 24;
+
+#[cfg(debug_assertions)]
+impl LockOwner {
+    const fn new() -> Self {
+        LockOwner {
+            hart: AtomicUsize::new(0),
+            name_ptr: AtomicPtr::new(core::ptr::null_mut()),
+            name_len: AtomicUsize::new(0),
+        }
+    }
+
+    fn acquired(&self) {
+        self.name_ptr
+            .store(core::ptr::null_mut(), Ordering::Relaxed);
+        self.hart.store(read_tp() as usize + 1, Ordering::Relaxed);
+    }
+
+    fn released(&self) {
+        self.hart.store(0, Ordering::Relaxed);
+    }
+
+    fn set_name(&self, name: &'static str) {
+        self.name_ptr
+            .store(core::ptr::null_mut(), Ordering::Relaxed);
+        self.name_len.store(name.len(), Ordering::Release);
+        self.name_ptr
+            .store(name.as_ptr() as *mut u8, Ordering::Release);
+    }
 
+    fn hart(&self) -> Option<usize> {
+        self.hart.load(Ordering::Relaxed).checked_sub(1)
+    }
+
+    // Best effort, as the holder may be swapping the name while we
+    // read it: if the pointer changed between the two halves we
+    // give up rather than pair one name's pointer with another's length.
+    fn name(&self) -> &'static str {
+        let ptr = self.name_ptr.load(Ordering::Acquire);
+        let len = self.name_len.load(Ordering::Acquire);
+        if ptr.is_null() || self.name_ptr.load(Ordering::Relaxed) != ptr {
+            return "?";
+        }
+        unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len)) }
+    }
+
+    // Called on each spin while waiting for the lock.
+    fn waiting(&self, spins: u64) {
+        let me = read_tp() as usize;
+        if self.hart() == Some(me) {
+            panic!(
+                "hart {} deadlocked on lock '{}' it already holds",
+                me,
+                self.name()
+            );
+        }
+        if spins == SPIN_WARN {
+            match self.hart() {
+                Some(holder) => log!(
+                    Warning,
+                    "hart {} spinning on lock '{}' held by hart {}",
+                    me,
+                    self.name(),
+                    holder
+                ),
+                None => log!(Warning, "hart {} spinning on lock '{}'", me, self.name()),
+            }
+        }
+    }
+}
+
+// Take two locks at once without risking an ABBA deadlock.
+// Both are always acquired lowest address first, whatever order
+// the caller names them in, so two harts locking the same pair
+// can't each end up holding one and waiting on the other.
+// The guards come back in the caller's order.
+pub fn lock2<'a, A, B, P: LockPolicy>(
+    a: &'a Lock<A, P>,
+    b: &'a Lock<B, P>,
+) -> (MutexGuard<'a, A, P>, MutexGuard<'a, B, P>) {
+    let a_addr = a as *const Lock<A, P> as usize;
+    let b_addr = b as *const Lock<B, P> as usize;
+    assert!(a_addr != b_addr, "lock2 on the same mutex twice");
+    if a_addr < b_addr {
+        let ga = a.lock();
+        (ga, b.lock())
+    } else {
+        let gb = b.lock();
+        (a.lock(), gb)
+    }
+}
+
+// Contention statistics, only compiled in with the `lock-stats`
+// feature so the plain lock path stays a bare compare_exchange
+// loop with no atomic counter updates.
+//
+// Counters are updated after the lock is acquired, so they
+// never add traffic to the contended lock word itself.
+
+// Point in time copy of a lock's counters.
+#[cfg(feature = "lock-stats")]
+#[derive(Clone, Copy)]
+pub struct LockStats {
+    pub acquisitions: u64,
+    pub spins: u64,
+}
+
+#[cfg(feature = "lock-stats")]
+struct LockCounters {
+    acquisitions: AtomicU64,
+    spins: AtomicU64,
+}
+
+#[cfg(feature = "lock-stats")]
+impl LockCounters {
+    const fn new() -> Self {
+        LockCounters {
+            acquisitions: AtomicU64::new(0),
+            spins: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, spins: u64) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if spins != 0 {
+            self.spins.fetch_add(spins, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> LockStats {
+        LockStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            spins: self.spins.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Fixed size table of tracked locks; no allocator yet.
+#[cfg(feature = "lock-stats")]
+struct LockRegistry {
+    entries: [Option<(&'static str, &'static LockCounters)>; NLOCKSTATS],
+}
+
+#[cfg(feature = "lock-stats")]
+impl LockRegistry {
+    fn register(&mut self, name: &'static str, counters: &'static LockCounters) {
+        for entry in self.entries.iter_mut() {
+            match entry {
+                Some((_, c)) if core::ptr::eq(*c, counters) => return,
+                None => {
+                    *entry = Some((name, counters));
+                    return;
+                }
+                _ => {}
+            }
+        }
+        // Table full, the lock just goes untracked.
+    }
+}
+
+#[cfg(feature = "lock-stats")]
+static LOCK_REGISTRY: Mutex<LockRegistry> = Mutex::new(LockRegistry {
+    entries: [None; NLOCKSTATS],
+});
+
+// Print every tracked lock over UART, most contended first.
+#[cfg(feature = "lock-stats")]
+pub fn dump_lock_stats() {
+    // Copy out so we don't hold the registry lock while printing.
+    let mut snapshot = [None; NLOCKSTATS];
+    for (slot, entry) in snapshot.iter_mut().zip(LOCK_REGISTRY.lock().entries.iter()) {
+        *slot = entry.map(|(name, c)| (name, c.snapshot()));
+    }
+    snapshot.sort_unstable_by(|a, b| {
+        let spins = |e: &Option<(&str, LockStats)>| e.map_or(0, |(_, s)| s.spins);
+        spins(b).cmp(&spins(a))
+    });
+
+    println!("lock                 acquisitions       spins");
+    for (name, stats) in snapshot.iter().flatten() {
+        println!(
+            "{:<20} {:>12} {:>11}",
+            name, stats.acquisitions, stats.spins
+        );
+    }
+}
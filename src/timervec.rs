@@ -2,39 +2,178 @@
 use crate::param;
 use crate::riscv;
 use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// Global tick count since boot. Lock free since it's
+// bumped on every timer interrupt.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Call once per timer interrupt.
+pub fn on_tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// Ticks elapsed since `earlier` (a previous ticks() value).
+// Wrapping so a timeout straddling the overflow still comes out right.
+pub fn ticks_since(earlier: u64) -> u64 {
+    ticks().wrapping_sub(earlier)
+}
+
+// Timer jitter checking, debug builds only. The tick handler calls
+// record_tick_time() and we compare each hart's time between ticks
+// with the configured interval, so a misprogrammed mtimecmp shows
+// up as a warning instead of a scheduler that's quietly off.
+#[cfg(debug_assertions)]
+static LAST_ARRIVAL: [AtomicU64; param::NHART] = [const { AtomicU64::new(0) }; param::NHART];
+#[cfg(debug_assertions)]
+static LAST_DELTA: [AtomicU64; param::NHART] = [const { AtomicU64::new(0) }; param::NHART];
+#[cfg(debug_assertions)]
+static MIN_DELTA: [AtomicU64; param::NHART] = [const { AtomicU64::new(u64::MAX) }; param::NHART];
+#[cfg(debug_assertions)]
+static MAX_DELTA: [AtomicU64; param::NHART] = [const { AtomicU64::new(0) }; param::NHART];
+
+// Warn when a tick is more than 1/JITTER_TOLERANCE of an
+// interval early or late.
+#[cfg(debug_assertions)]
+const JITTER_TOLERANCE: u64 = 10;
+
+#[cfg(debug_assertions)]
+pub fn record_tick_time() {
+    let hart = riscv::read_tp() as usize;
+    let now = riscv::read_time();
+    let last = LAST_ARRIVAL[hart].swap(now, Ordering::Relaxed);
+    if last == 0 {
+        // First tick on this hart, nothing to compare against.
+        return;
+    }
+
+    let delta = now - last;
+    LAST_DELTA[hart].store(delta, Ordering::Relaxed);
+    MIN_DELTA[hart].fetch_min(delta, Ordering::Relaxed);
+    MAX_DELTA[hart].fetch_max(delta, Ordering::Relaxed);
+
+    let expected = param::TIMEBASE_HZ / param::TICK_HZ;
+    if delta.abs_diff(expected) > expected / JITTER_TOLERANCE {
+        log!(
+            Warning,
+            "hart {} timer tick after {} ticks of time, expected {}",
+            hart,
+            delta,
+            expected
+        );
+    }
+}
+
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy)]
+pub struct TimerStats {
+    pub min: u64,
+    pub max: u64,
+    pub last: u64,
+}
+
+// Time between ticks on `hartid`, in time CSR units.
+#[cfg(debug_assertions)]
+pub fn timer_stats(hartid: usize) -> TimerStats {
+    TimerStats {
+        min: MIN_DELTA[hartid].load(Ordering::Relaxed),
+        max: MAX_DELTA[hartid].load(Ordering::Relaxed),
+        last: LAST_DELTA[hartid].load(Ordering::Relaxed),
+    }
+}
+
+// Watchdog for wedged harts: stuck in a loop with interrupts off,
+// deadlocked on a lock, and so on. Such a hart stops taking timer
+// ticks, so each tick stamps the time (and where the hart was) and
+// hart 0 periodically looks for stale stamps.
+#[cfg(feature = "watchdog")]
+static LAST_TICK: [AtomicU64; param::NHART] = [const { AtomicU64::new(0) }; param::NHART];
+#[cfg(feature = "watchdog")]
+static LAST_SEPC: [AtomicU64; param::NHART] = [const { AtomicU64::new(0) }; param::NHART];
+// Harts already reported, so a wedged hart warns once, not on
+// every check.
+#[cfg(feature = "watchdog")]
+static REPORTED: AtomicU64 = AtomicU64::new(0);
+
+// Call from the sup mode timer tick with the interrupted sepc.
+#[cfg(feature = "watchdog")]
+pub fn watchdog_tick(sepc: u64) {
+    let hart = riscv::read_tp();
+    LAST_SEPC[hart as usize].store(sepc, Ordering::Relaxed);
+    LAST_TICK[hart as usize].store(riscv::read_time(), Ordering::Relaxed);
+    REPORTED.fetch_and(!(1 << hart), Ordering::Relaxed);
+}
+
+// Run periodically on hart 0. Harts that have never ticked are
+// still booting (or nothing calls watchdog_tick() yet) and skipped.
+#[cfg(feature = "watchdog")]
+pub fn watchdog_check() {
+    let now = riscv::read_time();
+    for hart in (0..param::NHART).filter(|&h| crate::cpu::is_online(h as u64)) {
+        let last = LAST_TICK[hart].load(Ordering::Relaxed);
+        if last == 0 || now.wrapping_sub(last) < param::WATCHDOG_SECS * param::TIMEBASE_HZ {
+            continue;
+        }
+        if REPORTED.fetch_or(1 << hart, Ordering::Relaxed) & (1 << hart) == 0 {
+            log!(
+                Warning,
+                "hart {} has taken no timer tick in {}ms, last sepc {:#x}",
+                hart,
+                now.wrapping_sub(last) * 1000 / param::TIMEBASE_HZ,
+                LAST_SEPC[hart].load(Ordering::Relaxed)
+            );
+        }
+    }
+}
 
 // Core Local Interrupt Timer driver and functions.
 // Use memory mapped I/O on CLINT base address to program
 // interrupts and set up memory for mscratch.
 pub struct Clint {
     base: usize,
-    scratchpad: [[usize; 5]; param::NHART],
 }
 
+// Machine mode timer scratch area, one row per hart; mscratch holds
+// the address of the current hart's row. timervec runs before it
+// has any free registers or a stack, so it swaps this pointer into
+// a0 and works from there. Layout of a row, in u64 words:
+//   [0..3) SCRATCH_SAVE:     a1, a2, a3 spilled by timervec
+//   [3]    SCRATCH_MTIMECMP: address of this hart's mtimecmp
+//   [4]    SCRATCH_INTERVAL: cycles between timer interrupts
+// Same as xv6's timer_scratch. The offsets are fed to the asm below,
+// so they can't drift apart.
+const SCRATCH_SAVE: usize = 0;
+const SCRATCH_MTIMECMP: usize = 3;
+const SCRATCH_INTERVAL: usize = 4;
+const SCRATCH_WORDS: usize = 5;
+
+// Each row is only written by its own hart in Clint::init, before
+// machine interrupts are on, and afterwards only by timervec.
+static mut TIMER_SCRATCH: [[u64; SCRATCH_WORDS]; param::NHART] = [[0; SCRATCH_WORDS]; param::NHART];
+
 impl Clint {
-    // Register new clint and setup scratch memory.
     pub fn new(base: usize) -> Self {
-        let scratchpad = [[0; 5]; param::NHART];
-        Clint { base, scratchpad }
+        Clint { base }
     }
-    
-    // Initialize clint with appropriate addresses and interrupt interval in cycles.
+
+    // Initialize clint with appropriate addresses and interrupt interval in cycles,
+    // and point this hart's mscratch at its scratch row.
     pub fn init(&mut self, hartid: usize, interval: u64) {
-        // scratchpad[0..2] : timervec uses this area to save register values.
-        self.scratchpad[hartid][3] = self.base + 0x4000 + 8 * hartid;
-        self.scratchpad[hartid][4] = interval as usize;
-        
-        let scratchpad_addr = self.scratchpad.as_mut_ptr() as usize;
-        riscv::write_mscratch(scratchpad_addr);
+        unsafe {
+            let row = core::ptr::addr_of_mut!(TIMER_SCRATCH[hartid]);
+            (*row)[SCRATCH_MTIMECMP] = riscv::clint_mtimecmp(self.base, hartid as u64) as u64;
+            (*row)[SCRATCH_INTERVAL] = interval;
+            riscv::write_mscratch(row as usize);
+        }
     }
 }
-// Best/idiomatic practice here? ultimately going to need
-// the address of the function timervec to be stored in reg.
-//
-//pub fn timervec_fn() -> fn() {
 // xv6-riscv/kernel/kernelvec.S
-// 
-// 1. Store function arguments (a0-7)
+//
+// 1. Store function arguments (a1-3)
 // in first 3 slots in scratchpad
 //
 // 2. Schedule timer interrupt by
@@ -42,7 +181,7 @@ impl Clint {
 // who's addr is saved in scratchpad
 //
 // 3. Setup s/w interrupt with sip reg
-// (supervisor interrupt pending) for 
+// (supervisor interrupt pending) for
 // after this function returns with mret.
 //
 // 4. Restore regs.
@@ -52,12 +191,12 @@ global_asm!(
     .align 4
 timervec:
     csrrw a0, mscratch, a0
-    sd a1, 0(a0)
-    sd a2, 8(a0)
-    sd a3, 16(a0)
+    sd a1, {save0}(a0)
+    sd a2, {save1}(a0)
+    sd a3, {save2}(a0)
 
-    ld a1, 24(a0)
-    ld a2, 32(a0)
+    ld a1, {mtimecmp}(a0)
+    ld a2, {interval}(a0)
 
     # Recall a1 has addr of mtimecmp
     ld a3, 0(a1)
@@ -69,14 +208,21 @@ timervec:
     li a1, 2
     csrw sip, a1
 
-    ld a3, 16(a0)
-    ld a2, 8(a0)
-    ld a1, 0(a0)
+    ld a3, {save2}(a0)
+    ld a2, {save1}(a0)
+    ld a1, {save0}(a0)
     csrrw a0, mscratch, a0
 
     mret
-    "#
+    "#,
+    save0 = const 8 * SCRATCH_SAVE,
+    save1 = const 8 * (SCRATCH_SAVE + 1),
+    save2 = const 8 * (SCRATCH_SAVE + 2),
+    mtimecmp = const 8 * SCRATCH_MTIMECMP,
+    interval = const 8 * SCRATCH_INTERVAL,
 );
 
-//#[no_mangle]
-pub unsafe extern "C" fn timervec() {}
+// The asm above. Only ever used for its address, which goes in mtvec.
+extern "C" {
+    pub fn timervec();
+}
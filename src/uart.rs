@@ -2,18 +2,34 @@
 // Referenced from:
 // https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/uart.c
 // from https://github.com/sgmarz/osblog/tree/master/risc_v/src
-use core::fmt::Write;
 use core::fmt::Error;
+use core::fmt::Write;
 
+use crate::lateinit::LateInit;
 use crate::param::UART_BASE;
 use crate::spinlock::Mutex;
 
-const IER: usize = 1; // Interrupt Enable Register
-const LCR: usize = 3; // Line Control Register (baud rate stuff)
-const FCR: usize = 2; // FIFO Control Register (see uart layout in reference)
-//const LSR: usize = 2; // Line Status Register (ready to rx, ready to tx signals) 
+// NS16550A register offsets from the base address.
+pub const RBR: usize = 0; // Receive Buffer Register (read)
+pub const THR: usize = 0; // Transmit Holding Register (write)
+pub const IER: usize = 1; // Interrupt Enable Register
+pub const FCR: usize = 2; // FIFO Control Register (see uart layout in reference)
+pub const LCR: usize = 3; // Line Control Register (baud rate stuff)
+pub const LSR: usize = 5; // Line Status Register (ready to rx, ready to tx signals)
+                          // While LCR_DLAB is set, offsets 0 and 1 are the baud rate divisor.
+pub const DLL: usize = 0; // Divisor Latch LSB
+pub const DLM: usize = 1; // Divisor Latch MSB
 
-pub static WRITER: Mutex<Uart> = Uart::new();
+pub const IER_RX_ENABLE: u8 = 1 << 0; // Interrupt on received data
+pub const IER_TX_ENABLE: u8 = 1 << 1; // Interrupt on THR empty
+pub const FCR_FIFO_ENABLE: u8 = 1 << 0;
+pub const FCR_FIFO_CLEAR: u8 = 3 << 1; // Clear both rx and tx FIFOs
+pub const LCR_EIGHT_BITS: u8 = 3; // 8 bit words, no parity, 1 stop bit
+pub const LCR_DLAB: u8 = 1 << 7; // Divisor Latch Access Bit
+pub const LSR_DR: u8 = 1 << 0; // Data Ready, a byte is waiting in RBR
+pub const LSR_THRE: u8 = 1 << 5; // THR Empty, ready for the next byte
+
+pub static WRITER: LateInit<Mutex<Uart>> = LateInit::new();
 
 pub struct Uart {
     base_address: usize,
@@ -43,41 +59,59 @@ impl Uart {
             // Disable interrupts first.
             ptr.add(IER).write_volatile(0x0);
             // Mode in order to set baud rate.
-            ptr.add(LCR).write_volatile(1 << 7);
+            ptr.add(LCR).write_volatile(LCR_DLAB);
             // baud rate of 38.4k
-            ptr.add(0).write_volatile(0x03); // LSB (tx side)
-            ptr.add(1).write_volatile(0x00); // MST (rx side)
+            ptr.add(DLL).write_volatile(0x03);
+            ptr.add(DLM).write_volatile(0x00);
             // 8 bit words (no parity)
-            ptr.add(LCR).write_volatile(3); 
-            // Enabse and clear FIFO
-            ptr.add(FCR).write_volatile( 1<< 0 | 3 << 1);
+            // Also clears DLAB, back to the data registers.
+            ptr.add(LCR).write_volatile(LCR_EIGHT_BITS);
+            // Enable and clear FIFO
+            ptr.add(FCR)
+                .write_volatile(FCR_FIFO_ENABLE | FCR_FIFO_CLEAR);
             // Enable tx and rx interrupts
-            ptr.add(IER).write_volatile( 1 << 1 | 1 << 0);
+            ptr.add(IER).write_volatile(IER_TX_ENABLE | IER_RX_ENABLE);
         }
+        WRITER.init(Uart::new());
     }
 
     pub const fn new() -> Mutex<Self> {
-        Mutex::new(Uart { 
-                base_address: UART_BASE 
-            })
+        Mutex::new(Uart {
+            base_address: UART_BASE,
+        })
+    }
+
+    // Handle to the device that bypasses WRITER entirely.
+    /// # Safety
+    ///
+    /// Output can interleave with whoever holds the lock; only
+    /// for last resort diagnostics.
+    pub unsafe fn steal() -> Self {
+        Uart {
+            base_address: UART_BASE,
+        }
     }
 
     pub fn put(&mut self, c: u8) {
         let ptr = self.base_address as *mut u8;
         unsafe {
-            ptr.add(0).write_volatile(c);
+            // Wait for the previous byte to leave THR.
+            while ptr.add(LSR).read_volatile() & LSR_THRE == 0 {
+                core::hint::spin_loop();
+            }
+            ptr.add(THR).write_volatile(c);
         }
     }
 
     pub fn get(&mut self) -> Option<u8> {
         let ptr = self.base_address as *mut u8;
         unsafe {
-            if ptr.add(5).read_volatile() & 1 == 0 {
+            if ptr.add(LSR).read_volatile() & LSR_DR == 0 {
                 // The DR bit is 0, meaning no data
                 None
             } else {
                 // The DR bit is 1, meaning data!
-                Some(ptr.add(0).read_volatile())
+                Some(ptr.add(RBR).read_volatile())
             }
         }
     }
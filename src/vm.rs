@@ -0,0 +1,382 @@
+//! Virtual memory
+// Sv39 (or, with the `sv48` feature, Sv48) paging support. The page
+// table code itself is still to come; for now this holds the types
+// the rest of the kernel shares with it.
+use core::fmt;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::cpu::{online_mask, smp_started};
+use crate::param::{MAXVA, NHART, PAGE_SIZE, PAGING, TRAPFRAME, VA_BITS};
+use crate::riscv::{clint_clear_ipi, clint_send_ipi, read_tp, sfence_vma_all, sfence_vma_va};
+
+const VPN_BITS: u32 = 9;
+const PAGE_SHIFT: u32 = 12;
+
+// satp value for the page table rooted at physical address `root`.
+pub const fn make_satp(root: usize) -> u64 {
+    (PAGING.satp_mode << 60) | (root >> PAGE_SHIFT) as u64
+}
+
+// Past this many pages one full sfence.vma is cheaper than a
+// per page flush each, even counting the entries it needlessly
+// throws away. Below it, per page flushes keep the rest warm.
+pub const SFENCE_BATCH_THRESHOLD: usize = 64;
+
+// Why a VM operation failed, so callers can report something
+// more useful than "mapping failed".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    OutOfMemory,
+    AlreadyMapped,
+    Misaligned,
+    NotMapped,
+    BadFlags,
+    OutOfRange,
+    ShootdownTimeout,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            VmError::OutOfMemory => "out of memory for page table",
+            VmError::AlreadyMapped => "virtual address already mapped",
+            VmError::Misaligned => "address not page aligned",
+            VmError::NotMapped => "virtual address not mapped",
+            VmError::BadFlags => "invalid page table entry flags",
+            VmError::OutOfRange => "address range outside user space",
+            VmError::ShootdownTimeout => "TLB shootdown not acknowledged",
+        };
+        f.write_str(msg)
+    }
+}
+
+// A virtual address. Paging requires the bits above VA_BITS to be
+// copies of the top one (bit 38 for Sv39), anything else faults,
+// so we refuse to build one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VirtAddr(usize);
+
+impl VirtAddr {
+    pub const fn new(addr: usize) -> Option<Self> {
+        // Shift bit 38 up to 63 and arithmetic shift it back down;
+        // a canonical address survives unchanged.
+        let shift = usize::BITS - VA_BITS;
+        let extended = (((addr << shift) as isize) >> shift) as usize;
+        if extended == addr {
+            Some(VirtAddr(addr))
+        } else {
+            None
+        }
+    }
+
+    pub const fn addr(self) -> usize {
+        self.0
+    }
+
+    // None if the sum overflows or leaves canonical range, rather
+    // than wrapping to some unrelated address.
+    pub const fn checked_add(self, offset: usize) -> Option<Self> {
+        match self.0.checked_add(offset) {
+            Some(addr) => VirtAddr::new(addr),
+            None => None,
+        }
+    }
+
+    // Page table index for `level`, PAGING.levels - 1 being the
+    // root table.
+    pub const fn vpn(self, level: usize) -> usize {
+        (self.0 >> (PAGE_SHIFT + VPN_BITS * level as u32)) & ((1 << VPN_BITS) - 1)
+    }
+
+    pub const fn page_offset(self) -> usize {
+        self.0 & ((1 << PAGE_SHIFT) - 1)
+    }
+
+    // User programs may use [0, TRAPFRAME). The trap frame and
+    // trampoline pages above are mapped without the U bit.
+    pub const fn is_user(self) -> bool {
+        self.0 < TRAPFRAME
+    }
+
+    // Not a legal user address. Kernel and user tables are
+    // separate, so this says nothing about the kernel's own map.
+    pub const fn is_kernel(self) -> bool {
+        !self.is_user()
+    }
+}
+
+// Both Sv39 and Sv48 PTEs hold a 44 bit PPN, so 56 bit physical
+// addresses.
+pub const PA_BITS: u32 = 56;
+
+// A physical address, below 1 << PA_BITS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PhysAddr(usize);
+
+impl PhysAddr {
+    pub const fn new(addr: usize) -> Option<Self> {
+        if addr >> PA_BITS == 0 {
+            Some(PhysAddr(addr))
+        } else {
+            None
+        }
+    }
+
+    pub const fn addr(self) -> usize {
+        self.0
+    }
+
+    pub const fn checked_add(self, offset: usize) -> Option<Self> {
+        match self.0.checked_add(offset) {
+            Some(addr) => PhysAddr::new(addr),
+            None => None,
+        }
+    }
+}
+
+// Drop stale translations after unmapping `pages` pages from
+// `start`, e.g. at the end of an unmap_range. See
+// SFENCE_BATCH_THRESHOLD.
+pub fn flush_tlb_range(start: VirtAddr, pages: usize) {
+    if pages > SFENCE_BATCH_THRESHOLD {
+        sfence_vma_all();
+    } else {
+        // A range running off the end of the address space can't
+        // have been mapped; flush what precedes it.
+        let pages = (0..pages).map_while(|i| start.checked_add(i * PAGE_SIZE));
+        for va in pages {
+            sfence_vma_va(va.0);
+        }
+    }
+}
+
+// TLB shootdown. sfence.vma only flushes the local hart, so after
+// unmapping a page other harts may still use (the kernel map) each
+// of them has to flush it too. The initiator leaves the address in
+// every other online hart's mailbox, bumps that hart's request
+// number, pokes it with an IPI and waits until it has acked that
+// number. A hart is owed a flush while its ack trails its request.
+static SHOOTDOWN_VA: [AtomicUsize; NHART] = [const { AtomicUsize::new(0) }; NHART];
+static SHOOTDOWN_REQ: [AtomicUsize; NHART] = [const { AtomicUsize::new(0) }; NHART];
+static SHOOTDOWN_ACK: [AtomicUsize; NHART] = [const { AtomicUsize::new(0) }; NHART];
+// One shootdown at a time, so a mailbox is never overwritten
+// before its owner has read it.
+static SHOOTDOWN_BUSY: AtomicBool = AtomicBool::new(false);
+
+// Mailbox value meaning "flush everything". Used when a hart still
+// owes an earlier flush, so one mailbox can stand for both.
+const SHOOTDOWN_ALL: usize = usize::MAX;
+
+// How long the initiator waits for acks before giving up on the
+// harts that haven't answered.
+const SHOOTDOWN_ACK_SPINS: usize = 1_000_000;
+
+fn shootdown_owed(hart: usize) -> bool {
+    SHOOTDOWN_ACK[hart].load(Ordering::Acquire) != SHOOTDOWN_REQ[hart].load(Ordering::Relaxed)
+}
+
+// Flush `va` on every online hart. Only returns Ok once all of them
+// have; on Err some hart may still hold the old translation, so the
+// page must not be reused.
+pub fn tlb_shootdown(va: VirtAddr) -> Result<(), VmError> {
+    // Not a Mutex: while another hart's shootdown is in flight we
+    // have to keep answering it, or we'd both wait forever.
+    while SHOOTDOWN_BUSY
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        handle_tlb_shootdown();
+        spin_loop();
+    }
+
+    sfence_vma_va(va.0);
+    // Before smp_start() the other harts are still at the boot
+    // rendezvous with paging off, so there's nothing to flush.
+    // Parked harts have already left the online mask.
+    let me = read_tp();
+    let others = if smp_started() {
+        online_mask() & !(1 << me)
+    } else {
+        0
+    };
+    let targets = (0..NHART).filter(|&h| others & (1 << h) != 0);
+    for hart in targets.clone() {
+        // A hart that hasn't acked its last request may already have
+        // read that mailbox, so it must flush everything this time.
+        // Once its ack catches up it is done reading it.
+        let mailbox = if shootdown_owed(hart) {
+            SHOOTDOWN_ALL
+        } else {
+            va.0
+        };
+        SHOOTDOWN_VA[hart].store(mailbox, Ordering::Relaxed);
+        // Release publishes the mailbox along with the request.
+        SHOOTDOWN_REQ[hart].fetch_add(1, Ordering::Release);
+        clint_send_ipi(hart as u64);
+    }
+
+    let acked = (0..SHOOTDOWN_ACK_SPINS).any(|_| {
+        spin_loop();
+        !targets.clone().any(shootdown_owed)
+    });
+    SHOOTDOWN_BUSY.store(false, Ordering::Release);
+    if acked {
+        return Ok(());
+    }
+
+    // Their requests stay outstanding, so they still flush
+    // (everything, if another shootdown comes first) should they
+    // ever get to it.
+    let missing = targets
+        .filter(|&h| shootdown_owed(h))
+        .fold(0u64, |m, h| m | 1 << h);
+    log!(
+        Warning,
+        "TLB shootdown of {:#x}: no ack from harts {:#b}",
+        va.0,
+        missing
+    );
+    Err(VmError::ShootdownTimeout)
+}
+
+// Receiving side: flush and acknowledge if this hart has a request.
+// For the software interrupt handler. There isn't one that sees
+// msip yet (see cpu::halt_all_harts), so the idle loop and
+// cpu::spin_then_wfi() poll this.
+pub fn handle_tlb_shootdown() {
+    let hart = read_tp() as usize;
+    // Acquire pairs with the initiator's Release, so the mailbox
+    // read below is at least as new as request `req`. A newer
+    // request can only have widened it to SHOOTDOWN_ALL, since
+    // `req` isn't acked yet.
+    let req = SHOOTDOWN_REQ[hart].load(Ordering::Acquire);
+    if req != SHOOTDOWN_ACK[hart].load(Ordering::Relaxed) {
+        // Clear the IPI first: one sent after this is for a newer
+        // request, and we'll see it on the next call.
+        clint_clear_ipi(hart as u64);
+        match SHOOTDOWN_VA[hart].load(Ordering::Relaxed) {
+            SHOOTDOWN_ALL => sfence_vma_all(),
+            va => sfence_vma_va(va),
+        }
+        // Ack only what we've read; anything newer stays owed.
+        SHOOTDOWN_ACK[hart].store(req, Ordering::Release);
+    }
+}
+
+// Check a syscall's [va, va + len) lies entirely in user space
+// before anything touches it. A range that wraps past the top of
+// the address space is rejected rather than treated as small.
+pub const fn validate_user_range(va: VirtAddr, len: usize) -> Result<(), VmError> {
+    match va.checked_add(len) {
+        Some(end) if end.0 <= TRAPFRAME => Ok(()),
+        _ => Err(VmError::OutOfRange),
+    }
+}
+
+// Known decompositions, checked at compile time. This is the
+// easiest bit math in the kernel to get subtly wrong.
+// Entries are [vpn[levels - 1], ..., vpn[0], offset].
+const fn decomposes_to(addr: usize, want: &[usize]) -> bool {
+    let va = match VirtAddr::new(addr) {
+        Some(va) => va,
+        None => panic!("non canonical address in table"),
+    };
+    if want.len() != PAGING.levels + 1 || want[PAGING.levels] != va.page_offset() {
+        return false;
+    }
+    let mut i = 0;
+    while i < PAGING.levels {
+        if want[i] != va.vpn(PAGING.levels - 1 - i) {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+#[cfg(feature = "sv39")]
+const _: () = {
+    assert!(decomposes_to(0x0, &[0, 0, 0, 0]));
+    assert!(decomposes_to(0x1000, &[0, 0, 1, 0]));
+    assert!(decomposes_to(0x1234, &[0, 0, 1, 0x234]));
+    assert!(decomposes_to(0x20_0000, &[0, 1, 0, 0]));
+    assert!(decomposes_to(0x4000_0000, &[1, 0, 0, 0]));
+    assert!(decomposes_to(0x3f_ffff_f000, &[255, 511, 511, 0]));
+    assert!(decomposes_to(0xffff_ffc0_0000_0000, &[256, 0, 0, 0]));
+    assert!(decomposes_to(
+        0xffff_ffff_ffff_ffff,
+        &[511, 511, 511, 0xfff]
+    ));
+
+    // Upper bits must match bit 38.
+    assert!(VirtAddr::new(0x40_0000_0000).is_none());
+    assert!(VirtAddr::new(0x8000_0000_0000_0000).is_none());
+    assert!(VirtAddr::new(0xffff_ff80_0000_0000).is_none());
+};
+
+#[cfg(feature = "sv48")]
+const _: () = {
+    assert!(decomposes_to(0x0, &[0, 0, 0, 0, 0]));
+    assert!(decomposes_to(0x1000, &[0, 0, 0, 1, 0]));
+    assert!(decomposes_to(0x1234, &[0, 0, 0, 1, 0x234]));
+    assert!(decomposes_to(0x4000_0000, &[0, 1, 0, 0, 0]));
+    assert!(decomposes_to(0x40_0000_0000, &[0, 256, 0, 0, 0]));
+    assert!(decomposes_to(0x80_0000_0000, &[1, 0, 0, 0, 0]));
+    assert!(decomposes_to(0x7fff_ffff_f000, &[255, 511, 511, 511, 0]));
+    assert!(decomposes_to(0xffff_8000_0000_0000, &[256, 0, 0, 0, 0]));
+    assert!(decomposes_to(
+        0xffff_ffff_ffff_ffff,
+        &[511, 511, 511, 511, 0xfff]
+    ));
+
+    // Upper bits must match bit 47.
+    assert!(VirtAddr::new(0x8000_0000_0000).is_none());
+    assert!(VirtAddr::new(0x8000_0000_0000_0000).is_none());
+    assert!(VirtAddr::new(0xffff_0000_0000_0000).is_none());
+};
+
+// User range boundaries. This is a security check, so pin the
+// edges at compile time too.
+const fn user_ok(va: usize, len: usize) -> bool {
+    match VirtAddr::new(va) {
+        Some(va) => validate_user_range(va, len).is_ok(),
+        None => false,
+    }
+}
+
+const _: () = {
+    assert!(user_ok(0, 0));
+    assert!(user_ok(0, TRAPFRAME));
+    assert!(!user_ok(0, TRAPFRAME + 1));
+    assert!(user_ok(TRAPFRAME - 1, 1));
+    assert!(!user_ok(TRAPFRAME - 1, 2));
+    assert!(user_ok(TRAPFRAME, 0));
+    assert!(!user_ok(TRAPFRAME, 1));
+    // Wraparound: a huge len must not come out "small".
+    assert!(!user_ok(0x1000, usize::MAX));
+    assert!(!user_ok(0xffff_ffff_ffff_f000, 0x2000));
+    assert!(!user_ok(0x1000, usize::MAX - 0x800));
+
+    assert!(VirtAddr(TRAPFRAME - 1).is_user());
+    assert!(VirtAddr(TRAPFRAME).is_kernel());
+};
+
+// Address arithmetic at the top of each range.
+const _: () = {
+    assert!(VirtAddr(MAXVA - PAGE_SIZE)
+        .checked_add(PAGE_SIZE - 1)
+        .is_some());
+    // MAXVA itself has bit VA_BITS - 1 set and nothing above it.
+    assert!(VirtAddr(MAXVA - PAGE_SIZE).checked_add(PAGE_SIZE).is_none());
+    assert!(VirtAddr(usize::MAX).checked_add(1).is_none());
+    assert!(VirtAddr(usize::MAX - PAGE_SIZE)
+        .checked_add(PAGE_SIZE)
+        .is_some());
+
+    let top = (1 << PA_BITS) - PAGE_SIZE;
+    assert!(PhysAddr(top).checked_add(PAGE_SIZE - 1).is_some());
+    assert!(PhysAddr(top).checked_add(PAGE_SIZE).is_none());
+    assert!(PhysAddr(0).checked_add(usize::MAX).is_none());
+    assert!(PhysAddr::new(1 << PA_BITS).is_none());
+};